@@ -1,3 +1,6 @@
+# `CipherChatServer gen-cert` does all of this in one step, this script is
+# kept for setups that want to drive OpenSSL by hand.
+
 # Create unencrypted private key and a CSR (certificate signing request)
 openssl req -newkey rsa:2048 -nodes -keyout localhost.key -out localhost.csr
 
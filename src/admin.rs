@@ -0,0 +1,330 @@
+use std::{
+    fmt::Write,
+    fs,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::Path,
+    process,
+    sync::Arc,
+    time::Duration,
+};
+
+use futures_util::SinkExt;
+use log::{error, info};
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::Mutex,
+};
+use uuid::Uuid;
+
+use crate::{
+    blobs::BlobStore,
+    config::{AdminCommand, AdminConfig, Config},
+    protocol::ApiError,
+    user_handler::{self, UserDatabase},
+    presence, SessionDb,
+};
+
+/// answer of the running server to an admin command
+#[derive(serde::Serialize, serde::Deserialize)]
+struct AdminReply {
+    ok: bool,
+    output: String,
+}
+
+/// runs an admin command from the command line. It goes through the admin
+/// socket when a server is running and straight to the database otherwise.
+pub async fn run(command: AdminCommand, config: &Config) -> Result<String, String> {
+    let path = &config.admin.socket;
+
+    // the running server would keep its sessions and caches while the
+    // database changes under it, so a socket that doesnt answer is an error
+    if config.admin.enabled && path.exists() {
+        let stream = UnixStream::connect(path).await.map_err(|e| {
+            format!(
+                "couldnt reach the server on {}: {}, remove the socket if no server is running",
+                path.display(),
+                e
+            )
+        })?;
+        return send(stream, &command).await;
+    }
+
+    let user_db = Arc::new(Mutex::new(UserDatabase::new(config).await?));
+    let blobs = BlobStore::new(&config.blobs).await?;
+
+    execute(&user_db, &blobs, command).await.map_err(|e| e.to_string())
+}
+
+async fn send(stream: UnixStream, command: &AdminCommand) -> Result<String, String> {
+    let (read, mut write) = stream.into_split();
+
+    let mut request = serde_json::to_string(command).map_err(|e| e.to_string())?;
+    request.push('\n');
+    write
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| format!("couldnt send the command to the server: {}", e))?;
+
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await
+        .map_err(|e| format!("couldnt read the answer of the server: {}", e))?
+        .ok_or("the server closed the admin socket without answering")?;
+
+    let reply: AdminReply = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+    if reply.ok {
+        Ok(reply.output)
+    } else {
+        Err(reply.output)
+    }
+}
+
+/// listens on the admin socket of the running server, one JSON encoded
+/// `AdminCommand` per line is answered with one `AdminReply` line
+pub fn serve(
+    config: &AdminConfig,
+    send_timeout: Duration,
+    user_db: Arc<Mutex<UserDatabase>>,
+    session_db: SessionDb,
+    blobs: BlobStore,
+) -> Result<(), String> {
+    let path = &config.socket;
+
+    // a server that crashed leaves its socket behind, only a live one answers
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(format!("{} is in use, is another server running?", path.display()));
+        }
+        fs::remove_file(path).map_err(|e| format!("couldnt remove stale {}: {}", path.display(), e))?;
+    }
+
+    let listener = bind(path)?;
+    info!("Admin commands on: {}", path.display());
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle(stream, send_timeout, user_db.clone(), session_db.clone(), blobs.clone()));
+                }
+                Err(e) => error!("admin socket failed: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// binds the socket inside a directory only the owner can enter and moves it
+/// into place once it is restricted, nobody can connect in between
+fn bind(path: &Path) -> Result<UnixListener, String> {
+    let file_name = path.file_name().ok_or_else(|| format!("{} isnt a file", path.display()))?;
+    let dir = path.with_file_name(format!(".{}.{}", file_name.to_string_lossy(), process::id()));
+    let private = dir.join(file_name);
+
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| format!("couldnt create {}: {}", dir.display(), e))?;
+
+    let result = UnixListener::bind(&private)
+        .map_err(|e| format!("couldnt bind {}: {}", path.display(), e))
+        .and_then(|listener| {
+            fs::set_permissions(&private, fs::Permissions::from_mode(0o600))
+                .map_err(|e| format!("couldnt restrict {}: {}", path.display(), e))?;
+            fs::rename(&private, path).map_err(|e| format!("couldnt move {} into place: {}", path.display(), e))?;
+            Ok(listener)
+        });
+
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+async fn handle(
+    stream: UnixStream,
+    send_timeout: Duration,
+    user_db: Arc<Mutex<UserDatabase>>,
+    session_db: SessionDb,
+    blobs: BlobStore,
+) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let result = match serde_json::from_str::<AdminCommand>(&line) {
+            Ok(command) => {
+                info!("admin: {}", describe(&command));
+
+                let signed_out = match &command {
+                    AdminCommand::Delete { name }
+                    | AdminCommand::Disable { name }
+                    | AdminCommand::ResetPassword { name, .. } => Some(name.clone()),
+                    _ => None,
+                };
+
+                let result = execute(&user_db, &blobs, command).await;
+                if let (Ok(_), Some(name)) = (&result, signed_out) {
+                    sign_out(&session_db, &user_db, &name, send_timeout).await;
+                }
+                result.map_err(|e| e.to_string())
+            }
+            Err(e) => Err(format!("invalid admin command: {}", e)),
+        };
+
+        let reply = match result {
+            Ok(output) => AdminReply { ok: true, output },
+            Err(output) => AdminReply { ok: false, output },
+        };
+
+        let Ok(mut reply) = serde_json::to_string(&reply) else {
+            break;
+        };
+        reply.push('\n');
+        if write.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// closes every connection of a user, their tokens are already revoked
+async fn sign_out(session_db: &SessionDb, user_db: &Arc<Mutex<UserDatabase>>, username: &str, send_timeout: Duration) {
+    let sessions = session_db.lock().await.remove(username).unwrap_or_default();
+    if sessions.is_empty() {
+        return;
+    }
+
+    for (device, session) in sessions {
+        info!("signing out {} ({})", username, device);
+        let _ = tokio::time::timeout(send_timeout, async { session.ws_write.lock().await.close().await }).await;
+    }
+
+    presence::device_disconnected(session_db, user_db, username, send_timeout).await;
+}
+
+async fn execute(
+    user_db: &Arc<Mutex<UserDatabase>>,
+    blobs: &BlobStore,
+    command: AdminCommand,
+) -> Result<String, ApiError> {
+    let mut output = String::new();
+
+    match command {
+        AdminCommand::Users => {
+            let users = user_db.lock().await.list_users()?;
+
+            let _ = writeln!(output, "{:<24} {:>7} {:>7}  status", "name", "devices", "queued");
+            for user in users {
+                let _ = writeln!(
+                    output,
+                    "{:<24} {:>7} {:>7}  {}",
+                    user.name,
+                    user.devices,
+                    user.queued,
+                    status(user.disabled, user.locked_until)
+                );
+            }
+        }
+        AdminCommand::User { name } => {
+            let user = user_db.lock().await.user_summary(name.clone())?;
+            let devices = user_db.lock().await.user_devices(name)?;
+
+            let _ = writeln!(output, "{} ({})", user.name, status(user.disabled, user.locked_until));
+            for device in devices {
+                let _ = writeln!(output, "\ndevice {}", device.device);
+                let _ = writeln!(output, "  identity      {}", device.identity);
+                let _ = writeln!(output, "  prekey        {}", device.prekey);
+                let _ = writeln!(output, "  prekey since  {}", format_time(device.prekey_created_at));
+                let _ = writeln!(output, "  onetime keys  {}", device.onetime_keys);
+                let _ = writeln!(output, "  queued        {}", device.queued);
+            }
+        }
+        AdminCommand::Delete { name } => {
+            let deleted = user_db.lock().await.delete_user(name.clone())?;
+            for blob in &deleted {
+                blobs.remove(blob).await;
+            }
+
+            let _ = writeln!(output, "deleted {} and {} blobs", name, deleted.len());
+        }
+        AdminCommand::Disable { name } => {
+            user_db.lock().await.set_disabled(name.clone(), true)?;
+            let _ = writeln!(output, "disabled {}", name);
+        }
+        AdminCommand::Enable { name } => {
+            user_db.lock().await.set_disabled(name.clone(), false)?;
+            let _ = writeln!(output, "enabled {}", name);
+        }
+        AdminCommand::ResetPassword { name, password } => {
+            let generated = password.is_none();
+            let password = password.unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+
+            let password_hash = user_handler::hash_password(password.clone()).await?;
+            user_db.lock().await.reset_password(name.clone(), password_hash)?;
+
+            if generated {
+                let _ = writeln!(output, "new password of {}: {}", name, password);
+            } else {
+                let _ = writeln!(output, "reset the password of {}", name);
+            }
+        }
+        AdminCommand::Queue { name, device } => {
+            let entries = user_db.lock().await.inspect_queue(name, device)?;
+
+            let _ = writeln!(
+                output,
+                "{:<16} {:<36} {:<24} {:<23} {:>9}",
+                "device", "message", "author", "queued at", "bytes"
+            );
+            for entry in entries {
+                let _ = writeln!(
+                    output,
+                    "{:<16} {:<36} {:<24} {:<23} {:>9}",
+                    entry.device,
+                    entry.message_id,
+                    entry.author,
+                    format_time(entry.queued_at),
+                    entry.size
+                );
+            }
+        }
+        AdminCommand::PurgeQueue { name, device } => {
+            let purged = user_db.lock().await.purge_queue(name.clone(), device)?;
+            let _ = writeln!(output, "dropped {} queued messages of {}", purged, name);
+        }
+    }
+
+    Ok(output.trim_end().to_string())
+}
+
+/// the command for the log, without the password
+fn describe(command: &AdminCommand) -> String {
+    match command {
+        AdminCommand::ResetPassword { name, .. } => format!("reset password of {}", name),
+        command => format!("{:?}", command),
+    }
+}
+
+fn status(disabled: bool, locked_until: u64) -> String {
+    if disabled {
+        "disabled".to_string()
+    } else if locked_until > now() {
+        format!("locked until {}", format_time(locked_until))
+    } else {
+        "active".to_string()
+    }
+}
+
+/// a unix timestamp as UTC date and time
+fn format_time(secs: u64) -> String {
+    match OffsetDateTime::from_unix_timestamp(secs as i64) {
+        Ok(time) => format!("{} {:02}:{:02}:{:02}", time.date(), time.hour(), time.minute(), time.second()),
+        Err(_) => secs.to_string(),
+    }
+}
+
+fn now() -> u64 {
+    OffsetDateTime::now_utc().unix_timestamp() as u64
+}
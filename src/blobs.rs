@@ -0,0 +1,154 @@
+use std::{
+    io::SeekFrom,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+    time,
+};
+use uuid::Uuid;
+
+use crate::{
+    config::BlobConfig,
+    protocol::{ApiError, ErrorCode},
+    user_handler::UserDatabase,
+};
+
+/// how often expired blobs are removed
+const GC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Encrypted attachments on local disk, one file per blob named by its id.
+/// The server never sees the plaintext, the key travels inside the message
+/// referencing the blob. What has been uploaded so far is simply the length
+/// of the file, so an interrupted upload continues from there.
+#[derive(Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub async fn new(config: &BlobConfig) -> Result<Self, String> {
+        fs::create_dir_all(&config.path)
+            .await
+            .map_err(|e| format!("couldnt create {}: {}", config.path.display(), e))?;
+
+        Ok(Self {
+            dir: config.path.clone(),
+        })
+    }
+
+    /// creates the empty file uploads are appended to
+    pub async fn create(&self, id: &str) -> Result<(), ApiError> {
+        File::create(self.path(id)?)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("couldnt create blob {}: {}", id, e).into())
+    }
+
+    /// how many bytes of a blob have been uploaded
+    pub async fn len(&self, id: &str) -> Result<u64, ApiError> {
+        fs::metadata(self.path(id)?)
+            .await
+            .map(|m| m.len())
+            .map_err(|e| format!("couldnt read blob {}: {}", id, e).into())
+    }
+
+    /// appends a chunk, the offset has to match what was uploaded so far so a
+    /// resent chunk can't end up in the file twice. Returns the new length.
+    pub async fn append(&self, id: &str, offset: u64, chunk: &[u8]) -> Result<u64, ApiError> {
+        let len = self.len(id).await?;
+        if offset != len {
+            return Err(ApiError::new(
+                ErrorCode::BlobOffsetMismatch,
+                format!("Upload has to continue at offset {}", len),
+            ));
+        }
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(self.path(id)?)
+            .await
+            .map_err(|e| format!("couldnt open blob {}: {}", id, e))?;
+
+        file.write_all(chunk)
+            .await
+            .map_err(|e| format!("couldnt write blob {}: {}", id, e))?;
+        file.flush()
+            .await
+            .map_err(|e| format!("couldnt write blob {}: {}", id, e))?;
+
+        Ok(len + chunk.len() as u64)
+    }
+
+    /// reads up to `length` bytes starting at `offset`
+    pub async fn read(&self, id: &str, offset: u64, length: u64) -> Result<Vec<u8>, ApiError> {
+        let mut file = File::open(self.path(id)?)
+            .await
+            .map_err(|e| format!("couldnt open blob {}: {}", id, e))?;
+
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|e| format!("couldnt read blob {}: {}", id, e))?;
+
+        let mut data = Vec::new();
+        file.take(length)
+            .read_to_end(&mut data)
+            .await
+            .map_err(|e| format!("couldnt read blob {}: {}", id, e))?;
+
+        Ok(data)
+    }
+
+    pub async fn remove(&self, id: &str) {
+        let path = match self.path(id) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+
+        if let Err(e) = fs::remove_file(&path).await {
+            warn!("couldnt remove blob {}: {}", id, e);
+        }
+    }
+
+    /// periodically deletes blobs whose TTL ran out, fetched or not
+    pub fn collect_garbage(self, user_db: Arc<Mutex<UserDatabase>>) {
+        tokio::spawn(async move {
+            let mut interval = time::interval(GC_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let expired = match user_db.lock().await.remove_expired_blobs() {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("couldnt look up expired blobs: {}", e);
+                        continue;
+                    }
+                };
+
+                for id in &expired {
+                    self.remove(id).await;
+                }
+
+                if !expired.is_empty() {
+                    info!("removed {} expired blobs", expired.len());
+                }
+            }
+        });
+    }
+
+    /// blob ids are generated by the server, anything else could point
+    /// outside of the blob directory
+    fn path(&self, id: &str) -> Result<PathBuf, ApiError> {
+        let id = Uuid::parse_str(id)
+            .map_err(|_| ApiError::new(ErrorCode::BlobNotFound, format!("Invalid blob id {}", id)))?;
+
+        Ok(self.dir.join(id.to_string()))
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::IpAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyUsagePurpose, SanType,
+};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+
+use crate::config::TlsConfig;
+
+/// what `gen-cert` should create, replaces the OpenSSL steps of `script.sh`
+pub struct CertRequest {
+    pub ca_cert: PathBuf,
+    pub ca_key: PathBuf,
+    pub hosts: Vec<String>,
+    pub days: i64,
+    pub force: bool,
+}
+
+/// creates a development root CA and a leaf certificate signed by it, the
+/// leaf is written to the paths the server config points at
+pub fn generate(request: &CertRequest, tls: &TlsConfig) -> Result<(), String> {
+    let outputs = [&request.ca_cert, &request.ca_key, &tls.cert, &tls.key];
+    if !request.force {
+        if let Some(path) = outputs.iter().find(|path| path.exists()) {
+            return Err(format!(
+                "{} already exists, pass --force to overwrite it",
+                path.display()
+            ));
+        }
+    }
+
+    let now = OffsetDateTime::now_utc();
+
+    let mut ca_params = CertificateParams::default();
+    ca_params.distinguished_name = DistinguishedName::new();
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "CipherChat Development Root CA");
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    ca_params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    ca_params.not_before = now;
+    ca_params.not_after = now + Duration::days(5 * 365);
+    let ca = Certificate::from_params(ca_params).map_err(|e| e.to_string())?;
+
+    let mut leaf_params = CertificateParams::default();
+    leaf_params.distinguished_name = DistinguishedName::new();
+    leaf_params
+        .distinguished_name
+        .push(DnType::CommonName, request.hosts[0].clone());
+    leaf_params.subject_alt_names = request
+        .hosts
+        .iter()
+        .map(|host| match host.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(host.clone()),
+        })
+        .collect();
+    leaf_params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyEncipherment,
+    ];
+    leaf_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    leaf_params.use_authority_key_identifier_extension = true;
+    leaf_params.not_before = now;
+    leaf_params.not_after = now + Duration::days(request.days);
+    let leaf = Certificate::from_params(leaf_params).map_err(|e| e.to_string())?;
+
+    let ca_pem = ca.serialize_pem().map_err(|e| e.to_string())?;
+    let leaf_pem = leaf
+        .serialize_pem_with_signer(&ca)
+        .map_err(|e| e.to_string())?;
+
+    write_file(&request.ca_cert, &ca_pem, 0o644)?;
+    write_file(&request.ca_key, &ca.serialize_private_key_pem(), 0o600)?;
+    // the server sends the whole chain so clients only need to know the CA
+    write_file(&tls.cert, &format!("{}{}", leaf_pem, ca_pem), 0o644)?;
+    write_file(&tls.key, &leaf.serialize_private_key_pem(), 0o600)?;
+
+    let pin = STANDARD.encode(Sha256::digest(ca.get_key_pair().public_key_der()));
+
+    println!("root CA:     {} (key {})", request.ca_cert.display(), request.ca_key.display());
+    println!("certificate: {} (key {})", tls.cert.display(), tls.key.display());
+    println!("hosts:       {}", request.hosts.join(", "));
+    println!();
+    println!("CA SPKI pin (sha256, base64) for the client:");
+    println!("sha256/{}", pin);
+
+    Ok(())
+}
+
+fn write_file(path: &Path, content: &str, mode: u32) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .map_err(|e| format!("couldnt write {}: {}", path.display(), e))?;
+
+    file.write_all(content.as_bytes())
+        .map_err(|e| format!("couldnt write {}: {}", path.display(), e))
+}
@@ -0,0 +1,408 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand};
+
+/// config file read when neither `--config` nor `CIPHER_CONFIG` names one
+const DEFAULT_CONFIG: &str = "config.toml";
+
+/// Server settings, read from a TOML file. Every value has a default so the
+/// file only needs to contain what differs from it, unknown keys are refused
+/// so a typo doesn't silently fall back to the default.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<String>,
+    pub log_level: String,
+    pub tls: TlsConfig,
+    pub database: DatabaseConfig,
+    pub queue: QueueConfig,
+    pub keys: KeysConfig,
+    pub blobs: BlobConfig,
+    pub connection: ConnectionConfig,
+    pub rate_limit: RateLimitConfig,
+    pub admin: AdminConfig,
+    pub metrics: MetricsConfig,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// how many unacknowledged messages a single device may have queued,
+    /// messages to a device whose queue is full are refused with `QueueFull`
+    pub max_messages_per_device: usize,
+    /// largest attachment a binary frame may carry
+    pub max_attachment_bytes: usize,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysConfig {
+    /// how long a replaced signed prekey is still listed in fetched bundles
+    pub prekey_grace_period_secs: u64,
+    /// signed prekeys older than this are reported as stale
+    pub prekey_max_age_secs: u64,
+    /// users get asked to upload more one-time prekeys once they have fewer than this
+    pub onetime_key_low_watermark: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlobConfig {
+    /// directory the encrypted attachments are stored in
+    pub path: PathBuf,
+    /// blobs are deleted after this even if not every recipient fetched them
+    pub ttl_secs: u64,
+    /// largest blob that can be uploaded
+    pub max_blob_bytes: u64,
+    /// largest chunk of a single upload or download frame
+    pub chunk_bytes: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// how often the server pings every connection
+    pub ping_interval_secs: u64,
+    /// connections that sent nothing, not even a pong, for this long get closed
+    pub idle_timeout_secs: u64,
+    /// how long clients get to close their connections on SIGINT or SIGTERM
+    /// before the server exits anyway
+    pub shutdown_deadline_secs: u64,
+}
+
+/// Failed logins are counted per IP address and per account. After the free
+/// attempts every further one has to wait twice as long as the one before.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// failed logins before the backoff starts
+    pub free_attempts: u32,
+    /// wait after the first failure beyond the free attempts
+    pub backoff_base_secs: u64,
+    /// the backoff doesnt grow past this
+    pub backoff_max_secs: u64,
+    /// failed logins after which an account gets locked, 0 disables the lockout
+    pub lockout_attempts: u32,
+    /// how long a locked account stays locked, failures older than this are forgotten
+    pub lockout_secs: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// whether the running server accepts admin commands on the socket
+    pub enabled: bool,
+    /// unix socket the admin commands are sent to, only the owner can use it
+    pub socket: PathBuf,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// whether `/metrics` is served for Prometheus
+    pub enabled: bool,
+    /// address of the metrics endpoint, keep it local or firewalled
+    pub listen: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec!["localhost:9999".to_string()],
+            log_level: "info".to_string(),
+            tls: TlsConfig::default(),
+            database: DatabaseConfig::default(),
+            queue: QueueConfig::default(),
+            keys: KeysConfig::default(),
+            blobs: BlobConfig::default(),
+            connection: ConnectionConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            admin: AdminConfig::default(),
+            metrics: MetricsConfig::default(),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::from("localhost.crt"),
+            key: PathBuf::from("localhost.key"),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("test.db"),
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            max_messages_per_device: 10_000,
+            max_attachment_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        Self {
+            prekey_grace_period_secs: 7 * 24 * 60 * 60,
+            prekey_max_age_secs: 30 * 24 * 60 * 60,
+            onetime_key_low_watermark: 10,
+        }
+    }
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("blobs"),
+            ttl_secs: 7 * 24 * 60 * 60,
+            max_blob_bytes: 100 * 1024 * 1024,
+            chunk_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval_secs: 30,
+            idle_timeout_secs: 90,
+            shutdown_deadline_secs: 10,
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// how long a send to another connection may take, a stalled client
+    /// gets its messages from the queue later instead
+    pub fn send_timeout(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            backoff_base_secs: 1,
+            backoff_max_secs: 5 * 60,
+            lockout_attempts: 10,
+            lockout_secs: 15 * 60,
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: PathBuf::from("admin.sock"),
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "127.0.0.1:9100".to_string(),
+        }
+    }
+}
+
+/// Command line arguments, each of them can also be set through the
+/// environment and takes precedence over the config file.
+#[derive(Debug, Parser)]
+#[command(version, about = "CipherChat server", subcommand_precedence_over_arg = true)]
+pub struct Cli {
+    /// address to listen on, kept for compatibility with older start scripts
+    #[arg(value_name = "ADDR")]
+    addr: Option<String>,
+
+    /// path of the TOML config file, it has to exist when given [default: config.toml]
+    #[arg(short, long, env = "CIPHER_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// addresses to listen on, replaces the ones from the config file
+    #[arg(short, long, env = "CIPHER_LISTEN", value_delimiter = ',')]
+    listen: Vec<String>,
+
+    /// log filter, e.g. `info` or `CipherChatServer::node=debug`
+    #[arg(long, env = "CIPHER_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// PEM file with the certificate chain
+    #[arg(long, env = "CIPHER_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key
+    #[arg(long, env = "CIPHER_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+
+    /// path of the SQLite database
+    #[arg(long, env = "CIPHER_DATABASE")]
+    database: Option<PathBuf>,
+
+    /// unacknowledged messages kept per device
+    #[arg(long, env = "CIPHER_QUEUE_MAX_MESSAGES")]
+    queue_max_messages: Option<usize>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// create a development root CA and a certificate for the configured
+    /// TLS paths, then print the CA pin for the client
+    GenCert {
+        /// where the root CA certificate is written
+        #[arg(long, default_value = "rootCA.crt")]
+        ca_cert: PathBuf,
+
+        /// where the root CA private key is written
+        #[arg(long, default_value = "rootCA.key")]
+        ca_key: PathBuf,
+
+        /// hostnames or IP addresses the certificate is valid for
+        #[arg(long = "host", default_values = ["localhost", "127.0.0.1", "::1"])]
+        hosts: Vec<String>,
+
+        /// how many days the certificate is valid
+        #[arg(long, default_value_t = 365)]
+        days: i64,
+
+        /// overwrite existing certificates and keys
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// manage users and their queues, through the admin socket of a
+    /// running server or directly on the database of a stopped one
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand, serde::Serialize, serde::Deserialize)]
+pub enum AdminCommand {
+    /// list every account
+    Users,
+
+    /// show the key bundles and remaining onetime keys of every device of a user
+    User { name: String },
+
+    /// delete an account with its keys, queue, group memberships and blobs
+    Delete { name: String },
+
+    /// block logins of an account and sign out its sessions
+    Disable { name: String },
+
+    /// allow logins of a disabled account again
+    Enable { name: String },
+
+    /// set a new password, signs out every session and lifts a lockout
+    ResetPassword {
+        name: String,
+
+        /// the new password, a random one is generated and printed if left out
+        #[arg(long)]
+        password: Option<String>,
+    },
+
+    /// list the messages waiting for a user
+    Queue {
+        name: String,
+
+        /// only this device
+        #[arg(long)]
+        device: Option<String>,
+    },
+
+    /// drop the messages waiting for a user
+    PurgeQueue {
+        name: String,
+
+        /// only this device
+        #[arg(long)]
+        device: Option<String>,
+    },
+}
+
+impl Config {
+    /// loads the config file named on the command line and applies the
+    /// environment and command line overrides on top of it
+    pub fn load(cli: &Cli) -> Result<Self, String> {
+        let mut config = match &cli.config {
+            Some(path) => Config::read(path)?,
+            // only the implicit default file is optional
+            None if Path::new(DEFAULT_CONFIG).exists() => Config::read(Path::new(DEFAULT_CONFIG))?,
+            None => Config::default(),
+        };
+
+        if !cli.listen.is_empty() {
+            config.listen = cli.listen.clone();
+        }
+        if let Some(addr) = &cli.addr {
+            config.listen = vec![addr.clone()];
+        }
+        if let Some(v) = &cli.log_level {
+            config.log_level = v.clone();
+        }
+        if let Some(v) = &cli.tls_cert {
+            config.tls.cert = v.clone();
+        }
+        if let Some(v) = &cli.tls_key {
+            config.tls.key = v.clone();
+        }
+        if let Some(v) = &cli.database {
+            config.database.path = v.clone();
+        }
+        if let Some(v) = cli.queue_max_messages {
+            config.queue.max_messages_per_device = v;
+        }
+
+        if config.listen.is_empty() {
+            return Err("no listen address configured".to_string());
+        }
+        if config.connection.ping_interval_secs == 0 {
+            return Err("connection.ping_interval_secs has to be at least 1".to_string());
+        }
+        if config.connection.idle_timeout_secs <= config.connection.ping_interval_secs {
+            return Err("connection.idle_timeout_secs has to be longer than the ping interval".to_string());
+        }
+
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|e| format!("couldnt read {}: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| format!("couldnt parse {}: {}", path.display(), e))
+    }
+}
@@ -0,0 +1,72 @@
+use tokio_tungstenite::tungstenite::Message;
+
+/// Binary frames carry an encrypted attachment next to the usual routing
+/// information so it doesn't have to be base64 encoded into the ciphertext:
+///
+/// ```text
+/// [header length: u32 big endian][header: JSON][attachment bytes]
+/// ```
+///
+/// The header is whatever a text frame would contain in the protocol version
+/// of the connection, the attachment is passed on untouched.
+const LENGTH_PREFIX: usize = 4;
+
+/// splits a binary frame into its header and attachment
+pub fn decode(data: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    if data.len() < LENGTH_PREFIX {
+        return Err("binary frame is too short".to_string());
+    }
+
+    let (prefix, rest) = data.split_at(LENGTH_PREFIX);
+    let header_len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+
+    if header_len > rest.len() {
+        return Err(format!(
+            "binary frame header is {} bytes but only {} follow",
+            header_len,
+            rest.len()
+        ));
+    }
+
+    Ok(rest.split_at(header_len))
+}
+
+/// builds the frame a JSON header is sent with, a text frame unless it has an attachment
+pub fn encode(json: String, attachment: Option<&[u8]>) -> Message {
+    match attachment {
+        Some(attachment) => {
+            let mut data = Vec::with_capacity(LENGTH_PREFIX + json.len() + attachment.len());
+            data.extend_from_slice(&(json.len() as u32).to_be_bytes());
+            data.extend_from_slice(json.as_bytes());
+            data.extend_from_slice(attachment);
+            Message::Binary(data)
+        }
+        None => Message::Text(json),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_header_and_attachment() {
+        let Message::Binary(data) = encode("{}".to_string(), Some(b"attachment")) else {
+            panic!("expected a binary frame");
+        };
+
+        assert_eq!(decode(&data).unwrap(), (&b"{}"[..], &b"attachment"[..]));
+    }
+
+    #[test]
+    fn rejects_truncated_frame() {
+        let Message::Binary(data) = encode("{\"type\":\"send\"}".to_string(), Some(b"attachment")) else {
+            panic!("expected a binary frame");
+        };
+
+        // cut into the header
+        assert!(decode(&data[..10]).is_err());
+        // cut into the length prefix
+        assert!(decode(&data[..LENGTH_PREFIX - 1]).is_err());
+    }
+}
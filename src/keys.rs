@@ -0,0 +1,124 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use crate::util::{KeyBundle, KeyPairB64};
+
+/// length of the identity key, the signed prekey and the one-time prekeys
+pub const KEY_LENGTH: usize = 32;
+/// length of the signature over the signed prekey
+pub const SIGNATURE_LENGTH: usize = 64;
+
+/// Checks everything the server can check about a key bundle before it gets
+/// handed out to other users: all keys have to be base64 of the right length
+/// and the signed prekey has to be signed by the identity key.
+pub fn verify_bundle(bundle: &KeyBundle) -> Result<(), String> {
+    verify_signed_prekey(&bundle.identity, &bundle.prekey, &bundle.signature)?;
+    verify_onetime_keys(&bundle.onetime_keys)
+}
+
+/// Verifies the signature over the raw prekey bytes. The identity key is
+/// either an Ed25519 key or, like in Signal, an X25519 key whose signatures
+/// are made with XEdDSA, both are accepted.
+pub fn verify_signed_prekey(
+    identity: &KeyPairB64,
+    prekey: &KeyPairB64,
+    signature: &KeyPairB64,
+) -> Result<(), String> {
+    let identity = decode::<KEY_LENGTH>("identity key", &identity.public)?;
+    let prekey = decode::<KEY_LENGTH>("prekey", &prekey.public)?;
+    let signature = Signature::from_bytes(&decode::<SIGNATURE_LENGTH>("prekey signature", &signature.public)?);
+
+    let ed25519 = VerifyingKey::from_bytes(&identity).ok();
+    // XEdDSA always signs with the Edwards point whose sign bit is 0
+    let xeddsa = MontgomeryPoint(identity)
+        .to_edwards(0)
+        .map(VerifyingKey::from);
+
+    let valid = [ed25519, xeddsa]
+        .into_iter()
+        .flatten()
+        .any(|key| key.verify_strict(&prekey, &signature).is_ok());
+
+    if !valid {
+        return Err("prekey signature doesnt match the identity key".to_string());
+    }
+
+    Ok(())
+}
+
+pub fn verify_onetime_keys(keys: &[KeyPairB64]) -> Result<(), String> {
+    for (i, key) in keys.iter().enumerate() {
+        decode::<KEY_LENGTH>(&format!("onetime key {}", i), &key.public)?;
+    }
+
+    Ok(())
+}
+
+fn decode<const N: usize>(name: &str, value: &str) -> Result<[u8; N], String> {
+    let bytes = STANDARD
+        .decode(value)
+        .map_err(|e| format!("{} is not valid base64: {}", name, e))?;
+
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| format!("{} has {} bytes, expected {}", name, bytes.len(), N))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREKEY: &str = "E75P6uryBMf9M1j8nAByGIHRdCeBKCJ+xnTzf3/pe20=";
+
+    const ED25519_IDENTITY: &str = "A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=";
+    const ED25519_SIGNATURE: &str =
+        "GDiKis51P1VKEXgFMHUwY5dqwktVnieQQo7tnXKoZf7FEV0iUDv9m4SEk8e8Nqz+FvXw801bHWDqoLFXOnMmBQ==";
+
+    // the X25519 form of an Ed25519 key whose sign bit is 0
+    const XEDDSA_IDENTITY: &str = "GxtY3VDqFLYNoXt5DNAnVNlwybq4ZOuzwPMBb+UdP1c=";
+    const XEDDSA_SIGNATURE: &str =
+        "u11XxKT1jHFYU9eO7mhaCSk+o1Ip/3Iaud+ElTs0R/ZCuSqy0seKby3iz5Bwtmkb76Y3pc6pBlGz705FfMVUBg==";
+
+    fn key(public: &str) -> KeyPairB64 {
+        KeyPairB64 {
+            public: public.to_string(),
+            private: None,
+        }
+    }
+
+    #[test]
+    fn accepts_ed25519_signature() {
+        verify_signed_prekey(&key(ED25519_IDENTITY), &key(PREKEY), &key(ED25519_SIGNATURE)).unwrap();
+    }
+
+    #[test]
+    fn accepts_xeddsa_signature() {
+        verify_signed_prekey(&key(XEDDSA_IDENTITY), &key(PREKEY), &key(XEDDSA_SIGNATURE)).unwrap();
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let mut signature = STANDARD.decode(ED25519_SIGNATURE).unwrap();
+        signature[0] ^= 1;
+
+        let result = verify_signed_prekey(&key(ED25519_IDENTITY), &key(PREKEY), &key(&STANDARD.encode(signature)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_signature_of_other_identity() {
+        let result = verify_signed_prekey(&key(ED25519_IDENTITY), &key(PREKEY), &key(XEDDSA_SIGNATURE));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_key_length() {
+        let short = STANDARD.encode([1u8; KEY_LENGTH - 1]);
+
+        let error = verify_signed_prekey(&key(ED25519_IDENTITY), &key(&short), &key(ED25519_SIGNATURE)).unwrap_err();
+        assert_eq!(error, "prekey has 31 bytes, expected 32");
+
+        assert!(verify_onetime_keys(&[key(PREKEY), key(&short)]).is_err());
+    }
+}
@@ -1,72 +1,107 @@
-//! A simple echo server.
-//!
-//! You can test this out by running:
-//!
-//!     cargo run --example echo-server 127.0.0.1:12345
-//!
-//! And then in another window run:
-//!
-//!     cargo run --example client ws://127.0.0.1:12345/
-//!
-//! Type a message into the client window, press enter to send it and
-//! see it echoed back.
-
-use std::{
-    collections::HashMap,
-    env,
-    fs::{self, File},
-    hash::Hash,
-    io::{Error, Read},
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
-};
-
-use futures_util::{
-    future,
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt, TryStreamExt,
-};
-use lazy_static::lazy_static;
-use log::info;
-use node::CipherNode;
-use server::CipherServer;
-use std::io::{self, BufReader};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::Mutex,
-    time,
-};
-use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
-use util::OpAuthPayload;
-
-use rustls_pemfile::{certs, rsa_private_keys};
-use std::net::ToSocketAddrs;
-use std::path::{Path, PathBuf};
-use tokio::io::{copy, sink, split, AsyncWriteExt};
-use tokio_rustls::rustls::{Certificate, PrivateKey};
-
-use crate::{user_handler::UserDatabase, util::MsgPayload};
-type WsWrite = Arc<Mutex<SplitSink<WebSocketStream<TlsStream<TcpStream>>, Message>>>;
-type WsRead = Arc<Mutex<SplitStream<WebSocketStream<TlsStream<TcpStream>>>>>;
-
-
-mod user_handler;
-mod util;
-mod server;
-mod node;
-
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    let _ = env_logger::try_init();
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "localhost:9999".to_string());
-
-
-    let server = CipherServer::new(addr).await;
-
-    server.process().await;
-
-    Ok(())
+//! A simple echo server.
+//!
+//! You can test this out by running:
+//!
+//!     cargo run --example echo-server 127.0.0.1:12345
+//!
+//! And then in another window run:
+//!
+//!     cargo run --example client ws://127.0.0.1:12345/
+//!
+//! Type a message into the client window, press enter to send it and
+//! see it echoed back.
+
+use std::{collections::{HashMap, HashSet}, io::Error, process, sync::{atomic::AtomicI64, Arc}};
+
+use clap::Parser;
+use config::{Cli, Command, Config};
+use futures_util::stream::{SplitSink, SplitStream};
+use log::error;
+use server::CipherServer;
+use tokio::{net::TcpStream, sync::Mutex};
+use tokio_rustls::server::TlsStream;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+type WsSink = SplitSink<WebSocketStream<TlsStream<TcpStream>>, Message>;
+type WsWrite = Arc<Mutex<WsSink>>;
+type WsRead = Arc<Mutex<SplitStream<WebSocketStream<TlsStream<TcpStream>>>>>;
+/// online sessions by username and device id
+type SessionDb = Arc<Mutex<HashMap<String, HashMap<String, Session>>>>;
+
+/// an online device with the protocol version and capabilities its client speaks
+#[derive(Clone)]
+struct Session {
+    ws_write: WsWrite,
+    version: u32,
+    capabilities: HashSet<&'static str>,
+    /// users whose presence this device subscribed to
+    subscriptions: HashSet<String>,
+    /// queue id up to which the backlog was sent when the device came online
+    backlog_sent: Arc<AtomicI64>,
+}
+
+
+mod admin;
+mod blobs;
+mod certgen;
+mod config;
+mod frame;
+mod keys;
+mod metrics;
+mod migrations;
+mod protocol;
+mod rate_limit;
+mod user_handler;
+mod util;
+mod server;
+mod node;
+mod presence;
+mod tls;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // RUST_LOG still wins so single modules can be debugged without touching the config
+    let _ = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(config.log_level.as_str()),
+    )
+    .try_init();
+
+    if let Some(command) = cli.command {
+        let result = match command {
+            Command::GenCert { ca_cert, ca_key, hosts, days, force } => {
+                let request = certgen::CertRequest { ca_cert, ca_key, hosts, days, force };
+                certgen::generate(&request, &config.tls)
+            }
+            Command::Admin { command } => admin::run(command, &config).await.map(|output| println!("{}", output)),
+        };
+
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+
+    let mut server = match CipherServer::new(config).await {
+        Ok(server) => server,
+        Err(e) => {
+            error!("couldnt start server: {}", e);
+            process::exit(1);
+        }
+    };
+
+    server.process().await;
+
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,224 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    time,
+};
+
+use crate::{config::MetricsConfig, user_handler::UserDatabase, SessionDb};
+
+/// how long a scraper may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// a value that only goes up
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// a value that goes up and down
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// everything that is counted while the server runs, sessions and queues
+/// are looked up when the metrics are scraped instead
+pub struct Metrics {
+    pub connections: Gauge,
+    pub messages_live: Counter,
+    pub messages_queued: Counter,
+    pub logins_succeeded: Counter,
+    pub logins_failed: Counter,
+    pub bundle_fetches: Counter,
+    pub onetime_keys_exhausted: Counter,
+    pub tls_handshake_failures: Counter,
+    pub websocket_handshake_failures: Counter,
+}
+
+pub static METRICS: Metrics = Metrics {
+    connections: Gauge::new(),
+    messages_live: Counter::new(),
+    messages_queued: Counter::new(),
+    logins_succeeded: Counter::new(),
+    logins_failed: Counter::new(),
+    bundle_fetches: Counter::new(),
+    onetime_keys_exhausted: Counter::new(),
+    tls_handshake_failures: Counter::new(),
+    websocket_handshake_failures: Counter::new(),
+};
+
+/// serves the metrics in the Prometheus text format on `/metrics`, the chat
+/// keeps running without them if the address can't be bound
+pub async fn serve(config: &MetricsConfig, session_db: SessionDb, user_db: Arc<Mutex<UserDatabase>>) {
+    let listener = match TcpListener::bind(&config.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("couldnt bind {} for the metrics, serving none: {}", config.listen, e);
+            return;
+        }
+    };
+    info!("Metrics on: http://{}/metrics", config.listen);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle(stream, session_db.clone(), user_db.clone()));
+        }
+    });
+}
+
+/// answers a single HTTP request, scrapers open a new connection anyway
+async fn handle(mut stream: TcpStream, session_db: SessionDb, user_db: Arc<Mutex<UserDatabase>>) {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    // only the request line matters, the rest of the head is read so the
+    // scraper isnt reset while it is still sending
+    let head = time::timeout(REQUEST_TIMEOUT, async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8 * 1024 {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return false,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+        true
+    })
+    .await;
+
+    if !matches!(head, Ok(true)) {
+        debug!("dropping incomplete metrics request");
+        return;
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut line = request.lines().next().unwrap_or_default().split(' ');
+
+    let (status, body) = match (line.next(), line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&session_db, &user_db).await),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "only GET is supported\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn render(session_db: &SessionDb, user_db: &Arc<Mutex<UserDatabase>>) -> String {
+    let (users, sessions) = {
+        let session_db = session_db.lock().await;
+        (session_db.len(), session_db.values().map(|devices| devices.len()).sum::<usize>())
+    };
+
+    let depths = user_db.lock().await.queue_depths().unwrap_or_else(|e| {
+        info!("couldnt read the queue depths: {}", e);
+        Vec::new()
+    });
+    let queued: u64 = depths.iter().map(|(_, depth)| depth).sum();
+
+    let m = &METRICS;
+    let mut out = String::new();
+
+    metric(&mut out, "cipherchat_connections", "gauge", "Open WebSocket connections, authenticated or not.")
+        .sample("", m.connections.get());
+    metric(&mut out, "cipherchat_sessions", "gauge", "Authenticated device sessions.")
+        .sample("", sessions);
+    metric(&mut out, "cipherchat_authenticated_users", "gauge", "Users with at least one authenticated session.")
+        .sample("", users);
+    metric(&mut out, "cipherchat_queued_messages", "gauge", "Messages waiting for an acknowledgement.")
+        .sample("", queued);
+
+    let mut family = metric(&mut out, "cipherchat_queue_depth", "gauge", "Messages waiting for an acknowledgement per user.");
+    for (user, depth) in depths {
+        family.sample(&format!("user=\"{}\"", escape(&user)), depth);
+    }
+
+    metric(&mut out, "cipherchat_messages_routed_total", "counter", "Messages handed to a device, live if it was online and queued otherwise.")
+        .sample("delivery=\"live\"", m.messages_live.get())
+        .sample("delivery=\"queued\"", m.messages_queued.get());
+    metric(&mut out, "cipherchat_logins_total", "counter", "Password logins.")
+        .sample("result=\"success\"", m.logins_succeeded.get())
+        .sample("result=\"failure\"", m.logins_failed.get());
+    metric(&mut out, "cipherchat_bundle_fetches_total", "counter", "Key bundle fetches.")
+        .sample("", m.bundle_fetches.get());
+    metric(&mut out, "cipherchat_onetime_keys_exhausted_total", "counter", "Bundles handed out without a onetime key because none was left.")
+        .sample("", m.onetime_keys_exhausted.get());
+    metric(&mut out, "cipherchat_handshake_failures_total", "counter", "Connections that failed the TLS or WebSocket handshake.")
+        .sample("stage=\"tls\"", m.tls_handshake_failures.get())
+        .sample("stage=\"websocket\"", m.websocket_handshake_failures.get());
+
+    out
+}
+
+/// the samples of one metric, written after its `HELP` and `TYPE` lines
+struct Family<'a> {
+    out: &'a mut String,
+    name: &'a str,
+}
+
+impl Family<'_> {
+    fn sample(&mut self, labels: &str, value: impl std::fmt::Display) -> &mut Self {
+        if labels.is_empty() {
+            let _ = writeln!(self.out, "{} {}", self.name, value);
+        } else {
+            let _ = writeln!(self.out, "{}{{{}}} {}", self.name, labels, value);
+        }
+        self
+    }
+}
+
+fn metric<'a>(out: &'a mut String, name: &'a str, kind: &str, help: &str) -> Family<'a> {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    Family { out, name }
+}
+
+/// escapes a label value, usernames are picked by the clients
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,288 @@
+use log::info;
+use rusqlite::Connection;
+
+/// Every schema change gets appended here and is never edited afterwards.
+/// The schema version of a database is the number of migrations applied to
+/// it, which is tracked in `PRAGMA user_version`.
+///
+/// The first migrations use `IF NOT EXISTS` because databases from before
+/// the migrations existed already contain some of these tables while still
+/// being at version 0.
+const MIGRATIONS: &[&str] = &[
+    // 1: users and their key bundles
+    "
+    CREATE TABLE IF NOT EXISTS users (
+        user_id INTEGER PRIMARY KEY,
+        name TEXT UNIQUE NOT NULL,
+        password TEXT NOT NULL,
+        token TEXT UNIQUE NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS keybundles (
+        bundle_id INTEGER PRIMARY KEY,
+        identity TEXT NOT NULL,
+        prekey TEXT NOT NULL,
+        signature TEXT NOT NULL,
+        user_id      INTEGER NOT NULL,
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+    );
+    CREATE TABLE IF NOT EXISTS one_time_keys (
+        key TEXT NOT NULL,
+        bundle_id INTEGER NOT NULL,
+        FOREIGN KEY (bundle_id)
+            REFERENCES keybundles (bundle_id)
+    );
+    ",
+    // 2: messages waiting for an ack of the recipient
+    "
+    CREATE TABLE IF NOT EXISTS message_queue (
+        queue_id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        message_id TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        queued_at INTEGER NOT NULL,
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+    );
+    CREATE INDEX IF NOT EXISTS message_queue_user ON message_queue (user_id, queue_id);
+    CREATE INDEX IF NOT EXISTS message_queue_message ON message_queue (user_id, message_id);
+    ",
+    // 3: resumable session tokens, only their sha256 digest is stored. The
+    // random token every user got at registration was never used and goes.
+    "
+    CREATE TABLE IF NOT EXISTS tokens (
+        token_hash TEXT PRIMARY KEY,
+        user_id INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+    );
+    CREATE TABLE users_without_token (
+        user_id INTEGER PRIMARY KEY,
+        name TEXT UNIQUE NOT NULL,
+        password TEXT NOT NULL
+    );
+    INSERT INTO users_without_token (user_id, name, password) SELECT user_id, name, password FROM users;
+    DROP TABLE users;
+    ALTER TABLE users_without_token RENAME TO users;
+    ",
+    // 4: indexes for the bundle lookups
+    "
+    CREATE INDEX keybundles_user ON keybundles (user_id);
+    CREATE INDEX one_time_keys_bundle ON one_time_keys (bundle_id);
+    CREATE INDEX tokens_user ON tokens (user_id);
+    ",
+    // 5: signed prekey rotation
+    "
+    ALTER TABLE keybundles ADD COLUMN prekey_created_at INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE previous_prekeys (
+        bundle_id INTEGER NOT NULL,
+        prekey TEXT NOT NULL,
+        signature TEXT NOT NULL,
+        created_at INTEGER NOT NULL,
+        retired_at INTEGER NOT NULL,
+        FOREIGN KEY (bundle_id)
+            REFERENCES keybundles (bundle_id)
+    );
+    CREATE INDEX previous_prekeys_bundle ON previous_prekeys (bundle_id);
+    ",
+    // 6: multiple devices per account, existing rows belong to the default device
+    "
+    ALTER TABLE keybundles ADD COLUMN device_id TEXT NOT NULL DEFAULT 'default';
+    CREATE UNIQUE INDEX keybundles_device ON keybundles (user_id, device_id);
+    ALTER TABLE message_queue ADD COLUMN device_id TEXT NOT NULL DEFAULT 'default';
+    DROP INDEX message_queue_user;
+    DROP INDEX message_queue_message;
+    CREATE INDEX message_queue_device ON message_queue (user_id, device_id, queue_id);
+    CREATE INDEX message_queue_message ON message_queue (user_id, device_id, message_id);
+    ALTER TABLE tokens ADD COLUMN device_id TEXT NOT NULL DEFAULT 'default';
+    ",
+    // 7: group conversations
+    "
+    CREATE TABLE groups (
+        group_id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        admin_id INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        FOREIGN KEY (admin_id)
+            REFERENCES users (user_id)
+    );
+    CREATE TABLE group_members (
+        group_id TEXT NOT NULL,
+        user_id INTEGER NOT NULL,
+        joined_at INTEGER NOT NULL,
+        PRIMARY KEY (group_id, user_id),
+        FOREIGN KEY (group_id)
+            REFERENCES groups (group_id),
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+    );
+    CREATE INDEX group_members_user ON group_members (user_id);
+    ",
+    // 8: attachments of binary frames
+    "
+    ALTER TABLE message_queue ADD COLUMN attachment BLOB;
+    ",
+    // 9: blob store for large attachments
+    "
+    CREATE TABLE blobs (
+        blob_id TEXT PRIMARY KEY,
+        owner_id INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        complete INTEGER NOT NULL DEFAULT 0,
+        created_at INTEGER NOT NULL,
+        expires_at INTEGER NOT NULL,
+        FOREIGN KEY (owner_id)
+            REFERENCES users (user_id)
+    );
+    CREATE TABLE blob_recipients (
+        blob_id TEXT NOT NULL,
+        user_id INTEGER NOT NULL,
+        fetched INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (blob_id, user_id),
+        FOREIGN KEY (blob_id)
+            REFERENCES blobs (blob_id),
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+    );
+    CREATE INDEX blobs_expires ON blobs (expires_at);
+    ",
+    // 10: failed logins and account lockouts
+    "
+    CREATE TABLE login_failures (
+        user_id INTEGER PRIMARY KEY,
+        failures INTEGER NOT NULL,
+        last_failure_at INTEGER NOT NULL,
+        locked_until INTEGER NOT NULL DEFAULT 0,
+        FOREIGN KEY (user_id)
+            REFERENCES users (user_id)
+    );
+    ",
+    // 11: accounts disabled by an admin
+    "
+    ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
+    ",
+    // 12: presence
+    "
+    ALTER TABLE users ADD COLUMN last_seen INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN presence_visibility TEXT NOT NULL DEFAULT 'everyone';
+    ",
+    // 13: whether a device was already asked for more onetime keys
+    "
+    ALTER TABLE keybundles ADD COLUMN onetime_keys_notified INTEGER NOT NULL DEFAULT 0;
+    ",
+];
+
+/// the schema version this binary was built for
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+/// applies every migration the database hasn't seen yet, each one in its own
+/// transaction so a failing migration leaves the database at the last good version
+pub fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let version: u32 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+
+    if version > latest_version() {
+        return Err(format!(
+            "database schema version {} is newer than the {} this server supports, refusing to start",
+            version,
+            latest_version()
+        ));
+    }
+
+    // rebuilding a table drops it for a moment, which the foreign keys would
+    // refuse. Rebuilds keep the row ids, so the references stay intact.
+    conn.pragma_update(None, "foreign_keys", false).map_err(|e| e.to_string())?;
+    let result = apply(conn, version);
+    conn.pragma_update(None, "foreign_keys", true).map_err(|e| e.to_string())?;
+
+    result
+}
+
+fn apply(conn: &mut Connection, version: u32) -> Result<(), String> {
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let target = i as u32 + 1;
+        info!("migrating database to schema version {}", target);
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        tx.execute_batch(migration)
+            .map_err(|e| format!("migration {} failed: {}", target, e))?;
+        tx.pragma_update(None, "user_version", target)
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(conn: &Connection) -> u32 {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table)).unwrap();
+        let rows = stmt.query_map([], |row| row.get(1)).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn new_database_gets_every_migration() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), latest_version());
+
+        // running again has nothing left to do
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), latest_version());
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1).unwrap();
+
+        assert!(migrate(&mut conn).is_err());
+        assert_eq!(version(&conn), latest_version() + 1);
+    }
+
+    #[test]
+    fn database_from_before_the_migrations_keeps_its_data() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (name, password, token) VALUES ('alice', 'hash', 'token');
+             INSERT INTO keybundles (identity, prekey, signature, user_id) VALUES ('i', 'p', 's', 1);
+             INSERT INTO one_time_keys (key, bundle_id) VALUES ('k', 1);",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), latest_version());
+
+        let user: (i32, String, String) = conn
+            .query_row("SELECT user_id, name, password FROM users", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(user, (1, "alice".to_string(), "hash".to_string()));
+        assert!(!columns(&conn, "users").contains(&"token".to_string()));
+
+        let keys: u32 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM one_time_keys a JOIN keybundles b ON a.bundle_id = b.bundle_id WHERE b.user_id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(keys, 1);
+    }
+}
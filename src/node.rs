@@ -1,106 +1,191 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, thread};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+};
 
 use log::{debug, info};
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tokio_rustls::rustls::{Certificate, PrivateKey};
-
-use crate::{node, user_handler::UserDatabase, util::{MsgPayload, OpAuthPayload}, WsRead, WsWrite};
-
-use std::{
-    env,
-    fs::{self, File},
-    hash::Hash,
-    io::{Error, Read},
-    time::{SystemTime, UNIX_EPOCH},
+use tokio::sync::{watch, Mutex};
+
+use crate::{
+    blobs::BlobStore,
+    config::Config,
+    frame,
+    metrics::METRICS,
+    presence,
+    protocol::{
+        ApiError, ErrorCode, Request, RequestFrame, Response, ResponseFrame, ACTIONS, CAPABILITIES,
+        LEGACY_VERSION, PROTOCOL_VERSION,
+    },
+    user_handler::{self, UserDatabase},
+    util::{
+        BlobInfo, GroupInfo, KeyBundle, KeyPairB64, MsgContent, MsgPayload, PresenceVisibility, DEFAULT_DEVICE,
+    },
+    Session, SessionDb, WsRead, WsSink, WsWrite,
 };
 
-use futures_util::{
-    future,
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt, TryStreamExt,
-};
-use lazy_static::lazy_static;
-use rustls_pemfile::{certs, rsa_private_keys};
-use std::io::{self, BufReader};
-use std::net::ToSocketAddrs;
-use std::path::{Path, PathBuf};
-use tokio::io::{copy, sink, split, AsyncWriteExt};
+use std::time::Instant;
+
+use futures_util::{SinkExt, StreamExt};
 use tokio::time;
-use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+use tokio_rustls::server::TlsStream;
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 
 // #[derive(Clone)]
 pub struct CipherNode {
     addr: SocketAddr,
-    session_db: Arc<Mutex<HashMap<String, Arc<Mutex<CipherNode>>>>>,
+    config: Arc<Config>,
+    session_db: SessionDb,
     user_db: Arc<Mutex<UserDatabase>>,
-    msg_queue: Arc<Mutex<HashMap<String, Vec<MsgPayload>>>>,
+    blobs: BlobStore,
 
     ws_write: Option<WsWrite>,
     ws_read: Option<WsRead>,
 
     authenticated: bool,
-    username: Option<String>
+    username: Option<String>,
+    device: Option<String>,
+    token: Option<String>,
+
+    /// protocol version agreed on in the `hello`, version 1 until then
+    version: u32,
+    /// capabilities agreed on in the `hello`, all of them until then
+    capabilities: HashSet<&'static str>,
+    /// id of the request being handled, echoed in the answer
+    request_id: Option<String>,
+
+    /// turns true once the server shuts down
+    shutdown: watch::Receiver<bool>,
 }
 
 impl CipherNode {
     pub fn new(
         addr: SocketAddr,
-        session_db: Arc<Mutex<HashMap<String, Arc<Mutex<CipherNode>>>>>,
+        config: Arc<Config>,
+        session_db: SessionDb,
         user_db: Arc<Mutex<UserDatabase>>,
-        msg_queue: Arc<Mutex<HashMap<String, Vec<MsgPayload>>>>,
+        blobs: BlobStore,
+        shutdown: watch::Receiver<bool>,
     ) -> Self {
         Self {
             addr,
+            config,
             session_db,
             user_db,
-            msg_queue,
+            blobs,
             ws_write: None,
             ws_read: None,
             authenticated: false,
-            username: None
+            username: None,
+            device: None,
+            token: None,
+            version: LEGACY_VERSION,
+            capabilities: CAPABILITIES.iter().copied().collect(),
+            request_id: None,
+            shutdown,
         }
     }
 
     pub async fn process(mut self, ws_stream: WebSocketStream<TlsStream<TcpStream>>){
-        
+
         info!("New WebSocket connection: {}", self.addr);
 
-        let (mut write, mut read) = ws_stream.split();
+        let (write, read) = ws_stream.split();
 
         // let ws_write = Arc::new(Mutex::new(write));
         // let ws_read = Arc::new(Mutex::new(read));
 
         let brr = Arc::new(Mutex::new(read));
+        let ws_write = Arc::new(Mutex::new(write));
         self.ws_read = Some(brr.clone());
-        self.ws_write = Some(Arc::new(Mutex::new(write)));
+        self.ws_write = Some(ws_write.clone());
+
+        let ping_interval = time::Duration::from_secs(self.config.connection.ping_interval_secs);
+        let idle_timeout = time::Duration::from_secs(self.config.connection.idle_timeout_secs);
+        let mut shutdown = self.shutdown.clone();
+        let mut closing = false;
 
         let node_ref = Arc::new(Mutex::new(self));
 
+        let mut read = brr.lock().await;
+        let mut heartbeat = time::interval_at(time::Instant::now() + ping_interval, ping_interval);
+        let mut last_seen = Instant::now();
+
+        loop {
+            let msg = tokio::select! {
+                msg = read.next() => match msg {
+                    Some(Ok(v)) => v,
+                    _ => break,
+                },
+                _ = heartbeat.tick() => {
+                    if last_seen.elapsed() > idle_timeout {
+                        info!("{} didnt answer for {:?}, closing the connection", node_ref.lock().await.addr, idle_timeout);
+                        // a half-open connection may never take the close frame
+                        let _ = time::timeout(ping_interval, async {
+                            ws_write.lock().await.close().await
+                        }).await;
+                        break;
+                    }
+
+                    let ping = time::timeout(ping_interval, async {
+                        ws_write.lock().await.send(Message::Ping(Vec::new())).await
+                    }).await;
+                    if !matches!(ping, Ok(Ok(_))) {
+                        info!("couldnt ping {}, closing the connection", node_ref.lock().await.addr);
+                        break;
+                    }
+                    continue;
+                }
+                _ = shutdown.changed(), if !closing => {
+                    closing = true;
+                    node_ref.lock().await.announce_shutdown().await;
+
+                    // the loop keeps reading until the client answers the close frame
+                    let _ = time::timeout(ping_interval, async {
+                        ws_write.lock().await.close().await
+                    }).await;
+                    continue;
+                }
+            };
+
+            // pongs and everything else show that the other side is still there
+            last_seen = Instant::now();
 
-        while let Some(Ok(msg)) = brr.lock().await.next().await {
             match msg {
                 Message::Text(txt) => {
                     // write.send(Message::Text(format!("Echo: {txt}"))).await.unwrap();
-                    let msg: MsgPayload = serde_json::from_str(&txt).unwrap();
-                    debug!("received: {:?}", msg);
-                    node_ref.clone().lock().await.message_handler(
-                        msg,
-                        node_ref.clone()
-                    )
-                    .await;
+                    node_ref.lock().await.handle_frame(txt.as_bytes(), None).await;
+                }
+                Message::Binary(data) => {
+                    match frame::decode(&data) {
+                        Ok((header, attachment)) => {
+                            node_ref.lock().await.handle_frame(header, Some(attachment.to_vec())).await;
+                        }
+                        Err(e) => {
+                            let error = ApiError::new(ErrorCode::MalformedFrame, e);
+                            node_ref.lock().await.reject(error, None).await;
+                        }
+                    }
+                }
+                Message::Ping(_) => {
+                    // tungstenite already queued the pong, flushing sends it right away
+                    let _ = ws_write.lock().await.flush().await;
                 }
-                Message::Binary(_) => todo!(),
-                Message::Ping(_) => todo!(),
-                Message::Pong(_) => todo!(),
+                Message::Pong(_) => {}
                 Message::Close(_) => {
-                    println!("conn closed")
+                    debug!("client closed the connection")
                 }
-                Message::Frame(_) => todo!(),
+                // raw frames are only returned when writing, never when reading
+                Message::Frame(_) => {}
             }
         }
 
+        drop(read);
+
         node_ref.lock().await.cleanup().await;
 
         info!("connection ended");
@@ -111,64 +196,229 @@ impl CipherNode {
         if self.username.is_none(){
             return;
         }
-        
+
         debug!("cleaning up({})...", self.username.clone().unwrap());
 
-        let x = self.session_db.clone();
-        x.lock().await.remove(&self.username.clone().unwrap());
+        self.remove_session().await;
+    }
 
+    /// tells the client why its connection is about to be closed
+    async fn announce_shutdown(&mut self) {
+        info!("closing the connection to {} for the shutdown", self.addr);
+
+        self.request_id = None;
+        self.reply(Response::Shutdown {
+            message: "server shutting down".to_string(),
+        }, None).await;
     }
 
-    async fn message_handler(
-        &mut self,
-        mut message: MsgPayload,
-        node_ref: Arc<Mutex<CipherNode>>
-    ) {
-        if message.clone().auth.is_some() && message.content.is_none() {
-            debug!("is auth req");
+    /// removes the device of this connection from the online sessions
+    async fn remove_session(&self) {
+        let (Some(username), Some(device)) = (&self.username, &self.device) else {
+            return;
+        };
 
-            let auth = message.clone().auth.unwrap();
+        let removed = {
+            let mut db = self.session_db.lock().await;
 
-            match auth.action.as_str() {
-                "login" => self.login(auth, node_ref).await,
-                "register" => self.register(auth, node_ref).await,
-                "logout" => self.logout().await,
-                "fetch_bundle" => {
-                    self.fetch_bundle(message.clone()).await
+            match db.get_mut(username) {
+                // the device may have reconnected in the meantime, its new
+                // session stays
+                Some(devices) if self.owns(devices.get(device)) => {
+                    devices.remove(device);
+                    if devices.is_empty() {
+                        db.remove(username);
+                    }
+                    true
                 }
-                _ => println!("no such auth action"),
+                _ => false,
             }
-        } else {
-            if message.recipient != "" {
-                self.route_message(message).await;
+        };
+
+        // an admin who signed the user out or a newer connection of the
+        // device already took care of it
+        if removed {
+            presence::device_disconnected(&self.session_db, &self.user_db, username, self.config.connection.send_timeout()).await;
+        }
+    }
+
+    /// whether the session belongs to this connection
+    fn owns(&self, session: Option<&Session>) -> bool {
+        match (session, &self.ws_write) {
+            (Some(session), Some(ws_write)) => Arc::ptr_eq(&session.ws_write, ws_write),
+            _ => false,
+        }
+    }
+
+    /// reads the header of a text or binary frame and handles the request in it
+    async fn handle_frame(&mut self, header: &[u8], attachment: Option<Vec<u8>>) {
+        match self.parse(header) {
+            Ok((id, request)) => {
+                debug!("received: {:?}", request);
+                self.request_id = id;
+                self.message_handler(request, attachment).await;
             }
+            Err(e) => self.reject(e, frame_id(header)).await,
         }
     }
 
-    async fn send_message(&mut self, mut message: MsgPayload){
+    /// answers a frame that couldnt be turned into a request
+    async fn reject(&mut self, error: ApiError, id: Option<String>) {
+        info!("dropping frame from {}: {}", self.addr, error);
+        self.request_id = id;
+        self.fail("unknown", error).await;
+    }
 
-        let mut send_stream = self.ws_write.as_mut().unwrap().lock().await;
+    /// Until a client says hello it is treated as a version 1 client, so
+    /// the only tagged frame accepted before that is the `hello` itself.
+    fn parse(&self, header: &[u8]) -> Result<(Option<String>, Request), ApiError> {
+        let value: serde_json::Value = serde_json::from_slice(header).map_err(|e| {
+            ApiError::new(ErrorCode::MalformedFrame, format!("frame isnt valid JSON: {}", e))
+        })?;
+
+        if self.version >= PROTOCOL_VERSION || value.get("type").is_some() {
+            let action = value.get("type").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+            let frame: RequestFrame = serde_json::from_value(value).map_err(|e| {
+                if ACTIONS.contains(&action.as_str()) {
+                    ApiError::new(ErrorCode::MalformedFrame, format!("invalid {} request: {}", action, e))
+                } else {
+                    ApiError::new(ErrorCode::UnknownAction, format!("no such request type {:?}", action))
+                }
+            })?;
+
+            return match frame.request {
+                Request::Hello { .. } => Ok((frame.id, frame.request)),
+                _ if self.version >= PROTOCOL_VERSION => Ok((frame.id, frame.request)),
+                _ => Err(ApiError::new(
+                    ErrorCode::UnsupportedVersion,
+                    format!("send a hello before using protocol version {}", PROTOCOL_VERSION),
+                )),
+            };
+        }
 
-        let json = serde_json::to_string(&message).unwrap();
+        let msg: MsgPayload = serde_json::from_value(value)
+            .map_err(|e| ApiError::new(ErrorCode::MalformedFrame, format!("invalid message: {}", e)))?;
+        let id = Some(msg.message_id.clone());
 
-        send_stream.send(Message::Text(json)).await.unwrap();
+        Request::from_legacy(msg).map(|request| (id, request))
     }
 
-    async fn route_message(
+    async fn message_handler(
         &mut self,
-        mut message: MsgPayload
+        request: Request,
+        attachment: Option<Vec<u8>>
     ) {
-        if !self.authenticated{
+        if request.requires_auth() && !self.authenticated {
+            let error = ApiError::new(ErrorCode::NotAuthenticated, "Not authenticated");
+            self.fail(request.action(), error).await;
             return;
         }
 
-        debug!("routing message");
+        match request {
+            Request::Hello { versions, capabilities } => self.hello(versions, capabilities).await,
+            Request::Login { user, password, device, keybundle } => {
+                self.login(user, password, device_of(device), keybundle).await
+            }
+            Request::Resume { token } => self.resume(token).await,
+            Request::Revoke { token } => self.revoke(token).await,
+            Request::Register { user, password, device, keybundle } => {
+                self.register(user, password, device_of(device), keybundle).await
+            }
+            Request::Logout => self.logout().await,
+            Request::FetchBundle { user } => self.fetch_bundle(user).await,
+            Request::Ack { message_id, queue_id } => self.ack(message_id, queue_id).await,
+            Request::RotatePrekey { prekey, signature } => self.rotate_prekey(prekey, signature).await,
+            Request::UploadOnetimeKeys { keys } => self.upload_onetime_keys(keys).await,
+            Request::CountOnetimeKeys => self.count_onetime_keys().await,
+            Request::CreateGroup { name, members } => self.create_group(name, members).await,
+            Request::Invite { group, user } => self.invite(group, user).await,
+            Request::Leave { group } => self.leave(group).await,
+            Request::Kick { group, user } => self.kick(group, user).await,
+            Request::CreateBlob { size, recipients } => self.create_blob(size, recipients).await,
+            Request::UploadBlob { blob, offset } => {
+                self.upload_blob(blob, offset, attachment.unwrap_or_default()).await
+            }
+            Request::DownloadBlob { blob, offset, length } => self.download_blob(blob, offset, length).await,
+            Request::SubscribePresence { users } => self.subscribe_presence(users).await,
+            Request::UnsubscribePresence { users } => self.unsubscribe_presence(users).await,
+            Request::LastSeen { user } => self.last_seen(user).await,
+            Request::PresenceSettings { visibility } => self.presence_settings(visibility).await,
+            Request::Message { message_id, recipient, timestamp, content } => {
+                self.route_message(message_id, recipient, timestamp, content, attachment).await
+            }
+        }
+    }
 
-        if !self.user_db.lock().await.user_exists(message.recipient.clone()) {
-            info!("non existent user requested");
-            return;
+    /// picks the newest protocol version both sides speak and the capabilities
+    /// both sides know, a client not listing any gets all of them
+    async fn hello(&mut self, versions: Vec<u32>, capabilities: Vec<String>) {
+        // the answer is tagged no matter which version is picked, the client asked that way
+        let response = if self.authenticated {
+            self.error("hello", ApiError::new(ErrorCode::InvalidRequest, "hello has to be sent before logging in"))
+        } else {
+            match versions
+                .into_iter()
+                .filter(|version| (LEGACY_VERSION..=PROTOCOL_VERSION).contains(version))
+                .max()
+            {
+                Some(version) => {
+                    info!("{} speaks protocol version {}", self.addr, version);
+                    self.version = version;
+                    self.capabilities = CAPABILITIES
+                        .iter()
+                        .filter(|c| capabilities.is_empty() || capabilities.iter().any(|wanted| wanted == *c))
+                        .copied()
+                        .collect();
+
+                    Response::Hello {
+                        version,
+                        capabilities: CAPABILITIES
+                            .iter()
+                            .filter(|c| self.capabilities.contains(*c))
+                            .map(|c| c.to_string())
+                            .collect(),
+                    }
+                }
+                None => self.error("hello", ApiError::new(
+                    ErrorCode::UnsupportedVersion,
+                    format!(
+                        "No common protocol version, this server speaks {} to {}",
+                        LEGACY_VERSION, PROTOCOL_VERSION
+                    ),
+                )),
+            }
+        };
+
+        let json = self.tagged(response);
+        self.send(json, None).await;
+    }
+
+    async fn route_message(
+        &mut self,
+        message_id: String,
+        recipient: String,
+        timestamp: u64,
+        content: Option<MsgContent>,
+        attachment: Option<Vec<u8>>
+    ) {
+        if let Some(attachment) = &attachment {
+            if attachment.len() > self.config.queue.max_attachment_bytes {
+                info!(
+                    "dropping message {}, its attachment is {} bytes",
+                    message_id,
+                    attachment.len()
+                );
+                let error = ApiError::new(
+                    ErrorCode::PayloadTooLarge,
+                    format!("Attachments can be at most {} bytes", self.config.queue.max_attachment_bytes),
+                );
+                self.fail("message", error).await;
+                return;
+            }
         }
 
+        debug!("routing message");
+
         let username = match &self.username{
             Some(v) => v.to_string(),
             None => {
@@ -176,70 +426,253 @@ impl CipherNode {
             },
         };
 
-        message.author = username.clone();
+        let message = Response::Message {
+            message_id,
+            author: username.clone(),
+            recipient: recipient.clone(),
+            timestamp,
+            content,
+        }
+        .into_legacy(&recipient);
+
+        let group = self.user_db.lock().await.group(recipient.clone());
+        match group {
+            Ok(Some(group)) => {
+                if !group.members.contains(&username) {
+                    info!("{} isnt a member of group {}", username, group.id);
+                    let error = ApiError::new(ErrorCode::NotGroupMember, format!("You arent a member of {}", group.id));
+                    self.fail("message", error).await;
+                    return;
+                }
 
-        // IMPORTANT without this the lock will never be aquired
-        // if one messages themselves so the server will wait indefinitely
-        if message.recipient == username{
+                // the recipient stays the group id so members know where the message belongs
+                let members = group.members.into_iter().filter(|member| *member != username).collect();
+                if let Err(error) = self.deliver(members, message, attachment.as_deref()).await {
+                    self.fail("message", error).await;
+                }
+                return;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                info!("couldnt look up group {}: {}", recipient, e);
+                self.fail("message", e.into()).await;
+                return;
+            }
+        }
 
-            self.send_message(message).await;
+        if !self.user_db.lock().await.user_exists(recipient.clone()) {
+            info!("non existent user requested");
+            let error = ApiError::new(ErrorCode::UserNotFound, format!("User {} not found", recipient));
+            self.fail("message", error).await;
             return;
         }
 
-        
-        
-        let mut x = self.session_db.lock().await;
-        
+        if let Err(error) = self.deliver(vec![recipient], message, attachment.as_deref()).await {
+            self.fail("message", error).await;
+        }
+    }
+
+    /// hands a message to every device of the recipients
+    async fn deliver(&self, recipients: Vec<String>, message: MsgPayload, attachment: Option<&[u8]>) -> Result<(), ApiError> {
+        let mut targets = Vec::new();
+        for recipient in recipients {
+            let result = self.user_db.lock().await.devices(recipient.clone());
+            match result {
+                Ok(devices) => targets.extend(devices.into_iter().map(|device| (recipient.clone(), device))),
+                Err(e) => {
+                    info!("couldnt look up the devices of {}: {}", recipient, e);
+                    return Err(e.into());
+                }
+            }
+        }
+
+        self.deliver_to_devices(targets, message, attachment).await
+    }
+
+    /// keeps a message queued for each of the given devices until that device
+    /// acknowledges it and hands it to those that are online. If a queue is
+    /// full none of them get it and the error is returned.
+    async fn deliver_to_devices(
+        &self,
+        targets: Vec<(String, String)>,
+        message: MsgPayload,
+        attachment: Option<&[u8]>
+    ) -> Result<(), ApiError> {
+        // every device gets its own queue entry to acknowledge
+        let result = self.user_db.lock().await.enqueue_message(&targets, &message, attachment);
+        let queue_ids = match result {
+            Ok(v) => v,
+            Err(e) => {
+                info!("couldnt queue message {}: {}", message.message_id, e);
+                return Err(e);
+            }
+        };
+
+        // the session lock is released before sending so a slow socket
+        // doesnt stall routing for everybody else
+        let online: Vec<(String, String, i64, Session)> = {
+            let session_db = self.session_db.lock().await;
+            targets
+                .into_iter()
+                .zip(queue_ids.iter().copied())
+                .filter_map(|((recipient, device), queue_id)| {
+                    let session = session_db.get(&recipient)?.get(&device)?.clone();
+                    Some((recipient, device, queue_id, session))
+                })
+                .collect()
+        };
+
         debug!("successfully aquired session lock");
 
-        match x.get_mut(&message.recipient){
-            Some(node) => {
-                node.lock().await.send_message(message).await;
-            },
-            None => {
-                info!("target currently not online");
+        if online.is_empty() {
+            info!("target currently not online");
+        }
+
+        let send_timeout = self.config.connection.send_timeout();
+        let mut live = 0;
+        let mut undeliverable = Vec::new();
+        for (recipient, device, queue_id, session) in online {
+            let message = MsgPayload {
+                queue_id: Some(queue_id),
+                ..message.clone()
+            };
+            if let Some(capability) = missing_capability(&session.capabilities, &message, attachment) {
+                undeliverable.push((recipient, device, message, capability));
+                continue;
+            }
+            let Some(frame) = encode_queued(session.version, &message, attachment) else {
+                continue;
+            };
+            // a stalled recipient mustnt hold up the sender
+            let sent = time::timeout(send_timeout, async {
+                let mut send_stream = session.ws_write.lock().await;
+                // a device that just came online may have got it with its backlog
+                if message.queue_id.unwrap_or_default() <= session.backlog_sent.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                send_stream.send(frame).await
+            }).await;
+            match sent {
+                Ok(Ok(())) => live += 1,
+                Ok(Err(e)) => info!("sending to {} ({}) failed, message stays queued: {}", recipient, device, e),
+                Err(_) => info!("sending to {} ({}) timed out, message stays queued", recipient, device),
+            }
+        }
 
-                let mut queue = self.msg_queue.lock().await;
+        METRICS.messages_live.add(live);
+        METRICS.messages_queued.add(queue_ids.len() as u64 - live);
 
-                match queue.get_mut(&message.recipient.clone()) {
-                    Some(v) => {
-                        v.push(message);
-                    }
-                    None => {
-                        let mut z: Vec<MsgPayload> = Vec::new();
-                        z.push(message.clone());
-                        queue.insert(message.recipient, z);
-                    }
-                };
-            },
+        for (recipient, device, message, capability) in undeliverable {
+            self.drop_undeliverable(recipient, device, message, capability).await;
+        }
+
+        Ok(())
+    }
+
+    /// a device that lacks the capability a queued message needs would never
+    /// get it, so it is removed from that device's queue and the author told
+    async fn drop_undeliverable(&self, recipient: String, device: String, message: MsgPayload, capability: &str) {
+        let result = self.user_db.lock().await.ack_message(
+            recipient.clone(),
+            device.clone(),
+            message.message_id.clone(),
+            message.queue_id,
+        );
+        match result {
+            // the backlog and a live delivery may both have run into it
+            Ok(dropped) if dropped.is_empty() => return,
+            Ok(_) => info!("dropped message {} for {} ({}), it needs {}", message.message_id, recipient, device, capability),
+            Err(e) => {
+                info!("couldnt drop message {} for {} ({}): {}", message.message_id, recipient, device, e);
+                return;
+            }
+        }
+
+        // nobody to tell about events of the server
+        if message.author == "System" {
+            return;
+        }
+
+        let notice = Response::Undeliverable {
+            message_id: message.message_id,
+            user: recipient,
+            device: Some(device),
+            message: format!("The device doesnt support {}", capability),
+        }
+        .into_legacy(&message.author);
+
+        // notices never need a capability, so this doesnt come back here
+        let _ = Box::pin(self.deliver(vec![message.author], notice, None)).await;
+    }
+
+    async fn ack(&mut self, message_id: String, queue_id: Option<i64>) {
+        let username = self.username.clone().unwrap();
+        let device = self.device.clone().unwrap();
+
+        let result = self
+            .user_db
+            .lock()
+            .await
+            .ack_message(username.clone(), device.clone(), message_id.clone(), queue_id);
+        let acked = match result {
+            Ok(v) => v,
+            Err(e) => {
+                info!("couldnt ack message {}: {}", message_id, e);
+                self.fail("ack", e.into()).await;
+                return;
+            }
         };
-        // TODO proper error handling and shit
+
+        debug!("{} ({}) acked {}", username, device, message_id);
+
+        for msg in acked {
+            // status events are acked as well, but nobody gets notified about those
+            if msg.author == "System" {
+                continue;
+            }
+
+            let status = Response::Delivered {
+                message_id: msg.message_id,
+                user: username.clone(),
+                device: Some(device.clone()),
+            }
+            .into_legacy(&msg.author);
+
+            // nobody to tell if the status cant be queued, it was logged already
+            let _ = self.deliver(vec![msg.author], status, None).await;
+        }
     }
 
     async fn logout(&mut self){
 
-        if self.username.is_none() || self.authenticated == false{
+        if self.username.is_none() || !self.authenticated{
             return;
         }
 
         let username = self.username.clone().unwrap();
         debug!("logging {} out", username);
 
-        self.authenticated = false;
+        // a logged out session shouldnt be resumable anymore
+        if let Some(token) = self.token.take() {
+            if let Err(e) = self.user_db.lock().await.revoke_token(username.clone(), token) {
+                info!("couldnt revoke token of {}: {}", username, e);
+            }
+        }
 
-        let db = self.session_db.clone();
+        self.authenticated = false;
 
-        let mut db = db.lock().await;
+        self.remove_session().await;
 
         self.username = None;
-        db.remove(&username);
-
+        self.device = None;
     }
 
     async fn login(
         &mut self,
-        auth: OpAuthPayload,
-        node_ref: Arc<Mutex<CipherNode>>
+        username: String,
+        password: String,
+        device: String,
+        keybundle: Option<KeyBundle>
     ) {
         debug!("login req");
 
@@ -247,104 +680,180 @@ impl CipherNode {
             self.logout().await;
         }
 
-        let username = auth.user.as_str();
-        let password = auth.password.as_str();
+        // the password is checked without holding the database
+        let result = self.user_db.lock().await.begin_login(username.clone(), self.addr.ip());
+        let result = match result {
+            Ok(attempt) => match user_handler::check_password(password, attempt.password_hash.clone()).await {
+                Ok(check) => self.user_db.lock().await.finish_login(attempt, check, device.clone(), keybundle),
+                Err(e) => Err(e.into()),
+            },
+            Err(error) => Err(error),
+        };
 
-        // TODO: Implement login logic using user_db.login(username, password)
-        
-        match self.user_db.clone()
-            .lock()
-            .await
-            .login(username.to_string(), password.to_string())
-        {
+        match result {
             Ok(token) => {
-                let answer = MsgPayload {
-                    content: None,
-                    timestamp: self.getTimestamp(),
-                    auth: Some(OpAuthPayload {
-                        message: "Login successful".to_string(),
-                        action: "login".to_string(),
-                        user: username.to_string(),
-                        password: "".to_string(),
-                        keybundle: None,
-                        success: Some(true)
-                    }),
-                    message_id: uuid::Uuid::new_v4().to_string(),
-                    author: "System".to_string(),
-                    recipient: username.to_string(),
-                };
-                let json = serde_json::to_string(&answer).unwrap();
-                let mut send_stream = self.ws_write.clone();
-                let mut send_stream = send_stream.as_mut().unwrap().lock().await;
-                send_stream.send(Message::Text(json)).await.unwrap();
-
-                //fetch missed messages from queue
-                let queue_lock = self.msg_queue.clone();
-                let mut queue_lock = queue_lock.lock().await;
-                let queue = match queue_lock.get_mut(username) {
-                    Some(v) => v,
-                    None => &mut Vec::new(),
-                };
-                info!(
-                    "the following messages were sent while user was offline {:#?}",
-                    queue
-                );
+                METRICS.logins_succeeded.inc();
+                self.token = Some(token.to_string());
+                self.reply(Response::Authenticated {
+                    action: "login".to_string(),
+                    user: username.clone(),
+                    device: device.clone(),
+                    token: token.to_string(),
+                }, None).await;
+
+                self.authenticate(username, device).await;
+            }
+            Err(error) => {
+                METRICS.logins_failed.inc();
+                self.fail("login", error).await
+            }
+        }
+    }
 
-                thread::sleep(time::Duration::from_secs(1));
+    /// authenticates the connection with a token from a previous login
+    /// instead of the password, the token gets rotated in the process
+    async fn resume(&mut self, token: String) {
+        debug!("resume req");
 
-                for msg in queue.clone() {
-                    let json = serde_json::to_string(&msg).unwrap();
-                    send_stream.send(Message::Text(json)).await.unwrap();
+        if self.authenticated{
+            self.logout().await;
+        }
+
+        let result = self.user_db.lock().await.resume(token);
+
+        match result {
+            Ok((username, device, token)) => {
+                self.token = Some(token.to_string());
+                self.reply(Response::Authenticated {
+                    action: "resume".to_string(),
+                    user: username.clone(),
+                    device: device.clone(),
+                    token: token.to_string(),
+                }, None).await;
+
+                self.authenticate(username, device).await;
+            }
+            Err(error) => self.fail("resume", error).await,
+        }
+    }
+
+    /// revokes the given token, or every token of the user if none is given
+    async fn revoke(&mut self, token: Option<String>) {
+        let username = self.username.clone().unwrap();
+
+        let result = match token {
+            Some(token) => {
+                if self.token.as_ref() == Some(&token) {
+                    self.token = None;
                 }
+                self.user_db.lock().await.revoke_token(username, token)
+            }
+            None => {
+                self.token = None;
+                self.user_db.lock().await.revoke_tokens(username)
+            }
+        };
 
-                queue.clear();
+        match result {
+            Ok(_) => self.reply(Response::Revoked, None).await,
+            Err(error) => self.fail("revoke", error).await,
+        }
+    }
 
-                self.authenticate(username.to_owned(), node_ref).await;
+    /// resends everything this device hasnt acknowledged yet. The caller holds
+    /// the socket, so messages routed meanwhile wait until the backlog is out;
+    /// whatever gets queued while sending is picked up by the next round.
+    async fn send_queued(&self, send_stream: &mut WsSink, backlog_sent: &AtomicI64) -> Vec<(MsgPayload, &'static str)> {
+        let username = self.username.clone().unwrap();
+        let device = self.device.clone().unwrap();
+        let mut undeliverable = Vec::new();
+
+        loop {
+            let after = backlog_sent.load(Ordering::Relaxed);
+            let result = self.user_db.lock().await.queued_messages(username.clone(), device.clone(), after);
+            let queue = match result {
+                Ok(v) => v,
+                Err(e) => {
+                    info!("couldnt read message queue of {}: {}", username, e);
+                    return undeliverable;
+                }
+            };
+            if queue.is_empty() {
+                return undeliverable;
             }
-            Err(error) => {
-                
-                let answer = MsgPayload {
-                    content: None,
-                    timestamp: self.getTimestamp(),
-                    auth: Some(OpAuthPayload {
-                        message: format!("Login failed: {}", error).to_string(),
-                        action: "login".to_string(),
-                        user: username.to_string(),
-                        password: "".to_string(),
-                        keybundle: None,
-                        success: Some(false)
-                    }),
-                    message_id: uuid::Uuid::new_v4().to_string(),
-                    author: "System".to_string(),
-                    recipient: username.to_string(),
-                };
-                let json = serde_json::to_string(&answer).unwrap();
-                let mut send_stream = self.ws_write.as_mut().unwrap().lock().await;
-                send_stream.send(Message::Text(json)).await.unwrap();
+            info!(
+                "the following messages are still unacknowledged {:#?}",
+                queue.iter().map(|queued| &queued.message).collect::<Vec<_>>()
+            );
+
+            for queued in queue {
+                let queue_id = queued.message.queue_id.unwrap_or_default();
+                let attachment = queued.attachment.as_deref();
+                if let Some(capability) = missing_capability(&self.capabilities, &queued.message, attachment) {
+                    undeliverable.push((queued.message, capability));
+                } else if let Some(frame) = encode_queued(self.version, &queued.message, attachment) {
+                    if let Err(e) = send_stream.send(frame).await {
+                        info!("delivering queued messages to {} failed: {}", username, e);
+                        return undeliverable;
+                    }
+                }
+                backlog_sent.store(queue_id, Ordering::Relaxed);
             }
         }
     }
 
-    async fn authenticate(&mut self, username: String, node_ref: Arc<Mutex<CipherNode>>){
+    /// makes the device reachable and sends it its backlog before anything
+    /// routed to it afterwards
+    async fn authenticate(&mut self, username: String, device: String){
 
         self.authenticated = true;
 
-        let db = self.session_db.clone();
+        let ws_write = self.ws_write.clone().unwrap();
+        let mut send_stream = ws_write.lock().await;
+        let backlog_sent = Arc::new(AtomicI64::new(0));
+
+        let came_online = {
+            let mut db = self.session_db.lock().await;
+
+            info!("authenticated {} ({})", username, device);
+            self.username = Some(username.clone());
+            self.device = Some(device.clone());
+
+            let devices = db.entry(username.clone()).or_default();
+            let came_online = devices.is_empty();
+            devices.insert(device.clone(), Session {
+                ws_write: self.ws_write.clone().unwrap(),
+                version: self.version,
+                capabilities: self.capabilities.clone(),
+                subscriptions: HashSet::new(),
+                backlog_sent: backlog_sent.clone(),
+            });
+            came_online
+        };
 
-        let mut db = db.lock().await;
+        let undeliverable = self.send_queued(&mut send_stream, &backlog_sent).await;
+        drop(send_stream);
 
+        for (message, capability) in undeliverable {
+            self.drop_undeliverable(username.clone(), device.clone(), message, capability).await;
+        }
 
-        info!("authenticated {}", username);
-        self.username = Some(username.clone());        
-        db.insert(username, node_ref);
-        
+        let result = self.user_db.lock().await.touch_last_seen(username.clone());
+        if let Err(e) = result {
+            info!("couldnt update the last seen time of {}: {}", username, e);
+        }
 
+        if came_online {
+            presence::broadcast(&self.session_db, &self.user_db, &username, false, self.config.connection.send_timeout()).await;
+        }
     }
 
     async fn register(
         &mut self,
-        auth: OpAuthPayload,
-        node_ref: Arc<Mutex<CipherNode>>
+        username: String,
+        password: String,
+        device: String,
+        keybundle: KeyBundle
     ) {
         info!("requested register");
 
@@ -352,141 +861,559 @@ impl CipherNode {
             self.logout().await;
         }
 
-        let username = auth.user.as_str();
-        let password = auth.password.as_str();
-        let keybundle = auth.keybundle.unwrap();
-
-        // TODO: Implement register logic using user_db.register_user(username, password)
+        let result = match user_handler::hash_password(password).await {
+            Ok(password_hash) => self.user_db.lock().await.register_user(
+                username.clone(),
+                password_hash,
+                device.clone(),
+                keybundle,
+            ),
+            Err(e) => Err(e.into()),
+        };
 
-        // Example: (Assuming UserDatabase.register_user() returns Result<(), String>)
-        match self.user_db.clone().lock().await.register_user(
-            username.to_string(),
-            password.to_string(),
-            keybundle,
-        ) {
+        match result {
             Ok(token) => {
-                let msg = MsgPayload {
-                    content: None,
-                    timestamp: self.getTimestamp(),
-                    auth: Some(OpAuthPayload {
-                        message: "Registration successful".to_string(),
-                        action: "register".to_string(),
-                        user: username.to_string(),
-                        password: "".to_string(),
-                        keybundle: None,
-                        success: Some(true)
-                    }),
-                    message_id: uuid::Uuid::new_v4().to_string(),
-                    author: "System".to_string(),
-                    recipient: username.to_string(),
-                };
-                let mut send_stream = self.ws_write.clone();
-                let mut send_stream = send_stream.as_mut().unwrap().lock().await;
-                let json = serde_json::to_string(&msg).unwrap();
-                send_stream.send(Message::Text(json)).await.unwrap();
+                self.token = Some(token.to_string());
+                self.reply(Response::Authenticated {
+                    action: "register".to_string(),
+                    user: username.clone(),
+                    device: device.clone(),
+                    token: token.to_string(),
+                }, None).await;
+
+                self.authenticate(username, device).await;
+            }
+            Err(error) => self.fail("register", error).await,
+        }
+    }
 
-                self.authenticate(username.to_owned(), node_ref).await;
+    async fn fetch_bundle(&self, username: String) {
+        info!("requested bundle fetch");
 
+        let result = self.user_db.lock().await.fetch_bundles(username.clone());
 
-                // println!("sessions: {:#?}", session_db.lock().await);
-            }
-            Err(error) => {
-                let msg = MsgPayload {
-                    content: None,
-                    timestamp: self.getTimestamp(),
-                    auth: Some(OpAuthPayload {
-                        message: format!("Registration failed {}", error).to_string(),
-                        action: "register".to_string(),
-                        user: username.to_string(),
-                        password: "".to_string(),
-                        keybundle: None,
-                        success: Some(false)
-                    }),
-                    message_id: uuid::Uuid::new_v4().to_string(),
-                    author: "System".to_string(),
-                    recipient: username.to_string(),
-                };
-                let mut send_stream = self.ws_write.as_mut().unwrap().lock().await;
-                let json = serde_json::to_string(&msg).unwrap();
-                send_stream.send(Message::Text(json)).await.unwrap();
+        match result {
+            Ok(bundles) => {
+                METRICS.bundle_fetches.inc();
+                let devices: Vec<String> = bundles.iter().filter_map(|b| b.device.clone()).collect();
+
+                self.reply(Response::Bundles {
+                    user: username.clone(),
+                    keybundles: bundles,
+                }, None).await;
+
+                for device in devices {
+                    self.check_onetime_keys(username.clone(), device).await;
+                }
             }
+            Err(error) => self.fail("fetch_bundle", error).await,
         }
     }
 
-    async fn fetch_bundle(
-        &self,
-        mut og_msg: MsgPayload
-    ) {
-        if !self.authenticated{
-            return;
+    async fn rotate_prekey(&mut self, prekey: KeyPairB64, signature: KeyPairB64) {
+        info!("requested prekey rotation");
+
+        let username = self.username.clone().unwrap();
+        let device = self.device.clone().unwrap();
+
+        let result = self.user_db.lock().await.rotate_prekey(username, device, prekey, signature);
+
+        match result {
+            Ok(_) => self.reply(Response::PrekeyRotated, None).await,
+            Err(error) => self.fail("rotate_prekey", error).await,
         }
-        
-        info!("requested bundle fetch");
+    }
 
-        og_msg.author = self.username.clone().unwrap();
+    async fn upload_onetime_keys(&mut self, keys: Vec<KeyPairB64>) {
+        info!("requested onetime key upload");
 
-        let auth = og_msg.auth.unwrap();
+        let username = self.username.clone().unwrap();
+        let device = self.device.clone().unwrap();
 
-        let username = auth.user.as_str();
+        let result = self.user_db.lock().await.upload_onetime_keys(username, device, keys);
 
-        if !self.user_db.lock().await.user_exists(username.to_string()) {
-            info!("non existent user requested");
+        self.send_onetime_key_count("upload_onetime_keys", result).await;
+    }
+
+    async fn count_onetime_keys(&mut self) {
+        let username = self.username.clone().unwrap();
+        let device = self.device.clone().unwrap();
+
+        let result = self.user_db.lock().await.count_onetime_keys(username, device);
+
+        self.send_onetime_key_count("count_onetime_keys", result).await;
+    }
+
+    async fn send_onetime_key_count(&mut self, action: &str, result: Result<u64, ApiError>) {
+        match result {
+            Ok(count) => self.reply(Response::OnetimeKeys {
+                action: action.to_string(),
+                count,
+            }, None).await,
+            Err(error) => self.fail(action, error).await,
+        }
+    }
+
+    /// asks the device owning a bundle for new onetime keys once the remaining
+    /// ones drop below the watermark, only once until it uploads new ones so
+    /// fetching the bundle over and over can't fill its queue
+    async fn check_onetime_keys(&self, username: String, device: String) {
+        let watermark = self.config.keys.onetime_key_low_watermark;
+        let result = self
+            .user_db
+            .lock()
+            .await
+            .claim_onetime_keys_notice(username.clone(), device.clone(), watermark);
+        let remaining = match result {
+            Ok(Some(v)) => v,
+            Ok(None) => return,
+            Err(e) => {
+                info!("couldnt count onetime keys of {}: {}", username, e);
+                return;
+            }
+        };
+
+        info!("{} ({}) is running low on onetime keys ({} left)", username, device, remaining);
+
+        let notice = Response::OnetimeKeysLow {
+            device: Some(device.clone()),
+            count: remaining,
+        }
+        .into_legacy(&username);
+
+        let _ = self.deliver_to_devices(vec![(username, device)], notice, None).await;
+    }
+
+    async fn create_group(&mut self, name: String, members: Vec<String>) {
+        info!("requested group creation");
+
+        let username = self.username.clone().unwrap();
+
+        let result = self.user_db.lock().await.create_group(username, name, members);
+
+        self.finish_group_action("create_group", result, None).await;
+    }
+
+    async fn invite(&mut self, group_id: String, user: String) {
+        let username = self.username.clone().unwrap();
+
+        let result = self.user_db.lock().await.add_group_member(username, group_id, user);
+
+        self.finish_group_action("invite", result, None).await;
+    }
+
+    async fn leave(&mut self, group_id: String) {
+        let username = self.username.clone().unwrap();
+
+        let result = self.user_db.lock().await.remove_group_member(username.clone(), group_id, username);
+
+        self.finish_group_action("leave", result, None).await;
+    }
+
+    async fn kick(&mut self, group_id: String, user: String) {
+        let username = self.username.clone().unwrap();
+
+        let result = self.user_db.lock().await.remove_group_member(username, group_id, user.clone());
+
+        self.finish_group_action("kick", result, Some(user)).await;
+    }
+
+    /// answers a group action and tells every other member, and whoever got
+    /// kicked, about the new membership so they can rotate their sender keys
+    async fn finish_group_action(&mut self, action: &str, result: Result<GroupInfo, ApiError>, removed: Option<String>) {
+        let username = self.username.clone().unwrap();
+
+        let group = match result {
+            Ok(v) => v,
+            Err(error) => {
+                self.fail(action, error).await;
+                return;
+            }
+        };
+
+        self.reply(Response::Group {
+            action: action.to_string(),
+            group: group.clone(),
+        }, None).await;
+
+        let mut recipients = group.members.clone();
+        recipients.extend(removed);
+
+        for recipient in recipients {
+            if recipient == username {
+                continue;
+            }
+
+            let update = Response::GroupUpdate {
+                user: username.clone(),
+                group: group.clone(),
+            }
+            .into_legacy(&recipient);
+
+            let _ = self.deliver(vec![recipient], update, None).await;
+        }
+    }
+
+    /// registers a blob, the answer contains the id to upload it to
+    async fn create_blob(&mut self, size: u64, recipients: Vec<String>) {
+        info!("requested blob creation");
+
+        let username = self.username.clone().unwrap();
+
+        if size == 0 || size > self.config.blobs.max_blob_bytes {
+            let error = ApiError::new(
+                ErrorCode::PayloadTooLarge,
+                format!("Blobs have to be between 1 and {} bytes", self.config.blobs.max_blob_bytes),
+            );
+            self.fail("create_blob", error).await;
             return;
         }
 
-        match self.user_db.lock().await.fetch_bundle(username.to_string()) {
-            Ok(bundle) => {
-                let msg = MsgPayload {
-                    content: None,
-                    timestamp: self.getTimestamp(),
-                    auth: Some(OpAuthPayload {
-                        message: "fetched bundle".to_string(),
-                        action: "fetch_bundle".to_string(),
-                        user: username.to_string(),
-                        password: "".to_string(),
-                        keybundle: Some(bundle),
-                        success: Some(true)
-                    }),
-                    message_id: uuid::Uuid::new_v4().to_string(),
-                    author: "System".to_string(),
-                    recipient: og_msg.author.to_string(),
+        let result = self.user_db.lock().await.create_blob(
+            username,
+            size,
+            recipients.clone(),
+            self.config.blobs.ttl_secs,
+        );
+
+        let result = match result {
+            Ok(id) => match self.blobs.create(&id).await {
+                Ok(()) => Ok(id),
+                Err(e) => {
+                    // without its file the blob could never be uploaded
+                    let result = self.user_db.lock().await.delete_blob(id.clone());
+                    if let Err(e) = result {
+                        info!("couldnt forget blob {}: {}", id, e);
+                    }
+                    Err(e)
+                }
+            },
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(id) => {
+                let reply = BlobInfo {
+                    id,
+                    size,
+                    recipients,
+                    ..Default::default()
                 };
-                let mut send_stream = self.ws_write.clone();
-                let mut send_stream = send_stream.as_mut().unwrap().lock().await;
-                let json = serde_json::to_string(&msg).unwrap();
-                send_stream.send(Message::Text(json)).await.unwrap();
+                self.send_blob_reply("create_blob", reply, None).await;
+            }
+            Err(e) => self.fail("create_blob", e).await,
+        }
+    }
 
-                // println!("sessions: {:#?}", session_db.lock().await);
+    /// appends a chunk to a blob of this user, the answer tells where to
+    /// continue, even if the chunk was rejected
+    async fn upload_blob(&mut self, id: String, offset: u64, chunk: Vec<u8>) {
+        let username = self.username.clone().unwrap();
+
+        let result = self.user_db.lock().await.blob(username.clone(), id.clone());
+
+        let result = match result {
+            Ok(meta) if meta.owner != username => {
+                Err(ApiError::new(ErrorCode::InvalidRequest, "Only the owner can upload"))
             }
-            Err(error) => {
-                let msg = MsgPayload {
-                    content: None,
-                    timestamp: self.getTimestamp(),
-                    auth: Some(OpAuthPayload {
-                        message: format!("fetching bundle failed {}", error).to_string(),
-                        action: "fetch_bundle".to_string(),
-                        user: username.to_string(),
-                        password: "".to_string(),
-                        keybundle: None,
-                        success: Some(false)
+            Ok(meta) if meta.complete => Err(ApiError::new(ErrorCode::InvalidRequest, "Blob is already complete")),
+            Ok(_) if chunk.len() as u64 > self.config.blobs.chunk_bytes => Err(ApiError::new(
+                ErrorCode::PayloadTooLarge,
+                format!("Chunks can be at most {} bytes", self.config.blobs.chunk_bytes),
+            )),
+            // an offset close to u64::MAX doesnt fit either
+            Ok(meta) if offset.checked_add(chunk.len() as u64).is_none_or(|end| end > meta.size) => Err(ApiError::new(
+                ErrorCode::PayloadTooLarge,
+                format!("Chunk doesnt fit into the {} bytes of the blob", meta.size),
+            )),
+            Ok(meta) => self
+                .blobs
+                .append(&id, offset, &chunk)
+                .await
+                .map(|len| (meta.size, len)),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok((size, len)) => {
+                let complete = len == size;
+                if complete {
+                    if let Err(e) = self.user_db.lock().await.complete_blob(id.clone()) {
+                        info!("couldnt mark blob {} complete: {}", id, e);
+                    }
+                }
+
+                let reply = BlobInfo {
+                    id,
+                    size,
+                    offset: len,
+                    complete,
+                    ..Default::default()
+                };
+                self.send_blob_reply("upload_blob", reply, None).await;
+            }
+            Err(e) => {
+                let blob = match self.blobs.len(&id).await {
+                    Ok(len) => Some(BlobInfo {
+                        id,
+                        offset: len,
+                        ..Default::default()
                     }),
-                    message_id: uuid::Uuid::new_v4().to_string(),
-                    author: "System".to_string(),
-                    recipient: og_msg.author.to_string(),
+                    Err(_) => None,
                 };
-                let mut send_stream = self.ws_write.clone();
-                let mut send_stream = send_stream.as_mut().unwrap().lock().await;
-                let json = serde_json::to_string(&msg).unwrap();
-                send_stream.send(Message::Text(json)).await.unwrap();
+                let mut response = self.error("upload_blob", e);
+                if let Response::Error { blob: resume_at, .. } = &mut response {
+                    *resume_at = blob;
+                }
+                self.reply(response, None).await;
+            }
+        }
+    }
+
+    /// sends a range of a complete blob, a recipient that got the last byte has fetched it
+    async fn download_blob(&mut self, id: String, offset: u64, length: u64) {
+        let username = self.username.clone().unwrap();
+
+        let result = self.user_db.lock().await.blob(username.clone(), id.clone());
+
+        let meta = match result {
+            Ok(meta) if !meta.complete => {
+                Err(ApiError::new(ErrorCode::BlobIncomplete, "Blob isnt completely uploaded yet"))
+            }
+            Ok(meta) if offset >= meta.size => Err(ApiError::new(
+                ErrorCode::InvalidRequest,
+                format!("Offset is past the {} bytes of the blob", meta.size),
+            )),
+            other => other,
+        };
+
+        let meta = match meta {
+            Ok(v) => v,
+            Err(e) => {
+                self.fail("download_blob", e).await;
+                return;
+            }
+        };
+
+        let mut chunk_length = self.config.blobs.chunk_bytes.min(meta.size - offset);
+        if length > 0 {
+            chunk_length = chunk_length.min(length);
+        }
+
+        let data = match self.blobs.read(&id, offset, chunk_length).await {
+            Ok(v) => v,
+            Err(e) => {
+                self.fail("download_blob", e).await;
+                return;
+            }
+        };
+
+        let end = offset + data.len() as u64;
+        let reply = BlobInfo {
+            id: id.clone(),
+            size: meta.size,
+            offset,
+            length: data.len() as u64,
+            complete: true,
+            ..Default::default()
+        };
+        self.send_blob_reply("download_blob", reply, Some(data)).await;
+
+        if end == meta.size && meta.owner != username {
+            let result = self.user_db.lock().await.blob_fetched(username, id.clone());
+            match result {
+                Ok(true) => {
+                    info!("every recipient fetched blob {}, removing it", id);
+                    self.blobs.remove(&id).await;
+                }
+                Ok(false) => {}
+                Err(e) => info!("couldnt mark blob {} as fetched: {}", id, e),
+            }
+        }
+    }
+
+    /// subscribes this device to the presence of the users and answers with
+    /// how they look right now, nothing is subscribed if one of them doesnt exist
+    async fn subscribe_presence(&mut self, users: Vec<String>) {
+        let viewer = self.username.clone().unwrap();
+
+        let mut presence = Vec::new();
+        for user in &users {
+            match presence::lookup(&self.session_db, &self.user_db, user, &viewer).await {
+                Ok(info) => presence.push(info),
+                Err(error) => return self.fail("subscribe_presence", error).await,
+            }
+        }
+
+        self.session_mut(|session| session.subscriptions.extend(users)).await;
+
+        self.reply(Response::Presence {
+            action: "subscribe_presence".to_string(),
+            presence,
+        }, None).await;
+    }
+
+    async fn unsubscribe_presence(&mut self, users: Vec<String>) {
+        self.session_mut(|session| {
+            for user in &users {
+                session.subscriptions.remove(user);
+            }
+        }).await;
+
+        self.reply(Response::Presence {
+            action: "unsubscribe_presence".to_string(),
+            presence: Vec::new(),
+        }, None).await;
+    }
+
+    async fn last_seen(&mut self, user: String) {
+        let viewer = self.username.clone().unwrap();
+
+        match presence::lookup(&self.session_db, &self.user_db, &user, &viewer).await {
+            Ok(info) => {
+                self.reply(Response::Presence {
+                    action: "last_seen".to_string(),
+                    presence: vec![info],
+                }, None).await
+            }
+            Err(error) => self.fail("last_seen", error).await,
+        }
+    }
+
+    /// changes who may see the presence of the user, subscribers are told
+    /// right away so nobody keeps seeing them online after hiding
+    async fn presence_settings(&mut self, visibility: PresenceVisibility) {
+        let username = self.username.clone().unwrap();
+
+        let result = self.user_db.lock().await.set_presence_visibility(username.clone(), visibility);
+
+        match result {
+            Ok(()) => {
+                self.reply(Response::PresenceSettings { visibility }, None).await;
+                presence::broadcast(&self.session_db, &self.user_db, &username, true, self.config.connection.send_timeout()).await;
             }
+            Err(error) => self.fail("presence_settings", error).await,
         }
     }
 
-    fn getTimestamp(&self) -> u64 {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
-        timestamp
+    /// changes the online session of this device
+    async fn session_mut(&self, change: impl FnOnce(&mut Session)) {
+        let (Some(username), Some(device)) = (&self.username, &self.device) else {
+            return;
+        };
+
+        let mut db = self.session_db.lock().await;
+        if let Some(session) = db.get_mut(username).and_then(|devices| devices.get_mut(device)) {
+            change(session);
+        }
+    }
+
+    /// answers a blob action, downloaded data goes along as a binary frame
+    async fn send_blob_reply(&mut self, action: &str, blob: BlobInfo, data: Option<Vec<u8>>) {
+        if data.is_some() && !self.capabilities.contains("binary_frames") {
+            let error = ApiError::new(ErrorCode::InvalidRequest, "Downloads need the binary_frames capability");
+            return self.fail(action, error).await;
+        }
+
+        self.reply(Response::Blob {
+            action: action.to_string(),
+            blob,
+        }, data.as_deref()).await;
+    }
+
+    async fn fail(&self, action: &str, error: ApiError) {
+        let response = self.error(action, error);
+        self.reply(response, None).await;
+    }
+
+    /// the error frame for the request being handled
+    fn error(&self, action: &str, error: ApiError) -> Response {
+        Response::Error {
+            action: action.to_string(),
+            code: error.code,
+            message: error.message,
+            message_id: self.request_id.clone(),
+            retryable: error.code.retryable(),
+            retry_after: error.retry_after,
+            blob: None,
+        }
     }
+
+    /// answers the current request in the protocol version of this connection
+    async fn reply(&self, response: Response, attachment: Option<&[u8]>) {
+        let json = if self.version >= PROTOCOL_VERSION {
+            self.tagged(response)
+        } else {
+            let recipient = self.username.clone().unwrap_or_default();
+            serde_json::to_string(&response.into_legacy(&recipient)).unwrap()
+        };
+
+        self.send(json, attachment).await;
+    }
+
+    fn tagged(&self, response: Response) -> String {
+        let frame = ResponseFrame {
+            id: self.request_id.clone(),
+            queue_id: None,
+            response,
+        };
+        serde_json::to_string(&frame).unwrap()
+    }
+
+    async fn send(&self, json: String, attachment: Option<&[u8]>) {
+        let mut send_stream = self.ws_write.as_ref().unwrap().lock().await;
+        if let Err(e) = send_stream.send(frame::encode(json, attachment)).await {
+            info!("couldnt answer {}: {}", self.addr, e);
+        }
+    }
+}
+
+/// the device a request is made from, clients without device support only have one
+fn device_of(device: Option<String>) -> String {
+    device
+        .filter(|device| !device.is_empty())
+        .unwrap_or_else(|| DEFAULT_DEVICE.to_string())
+}
+
+/// the id of a frame that couldnt be read as a request, if it has one
+fn frame_id(header: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(header).ok()?;
+    let id = value.get("id").or_else(|| value.get("message_id"))?;
+
+    id.as_str().map(|id| id.to_string())
+}
+
+/// the capability a client didnt agree to in its `hello` but would need to
+/// receive a queued message
+fn missing_capability(
+    capabilities: &HashSet<&'static str>,
+    message: &MsgPayload,
+    attachment: Option<&[u8]>,
+) -> Option<&'static str> {
+    if attachment.is_some() && !capabilities.contains("binary_frames") {
+        return Some("binary_frames");
+    }
+    if message.auth.as_ref().is_some_and(|auth| auth.action == "group_update") && !capabilities.contains("groups") {
+        return Some("groups");
+    }
+    None
+}
+
+/// Queued messages are stored the way version 1 clients receive them,
+/// version 2 clients get the event they stand for. Check `missing_capability`
+/// first, this doesnt.
+fn encode_queued(version: u32, message: &MsgPayload, attachment: Option<&[u8]>) -> Option<Message> {
+    let json = if version >= PROTOCOL_VERSION {
+        let response = Response::from_legacy(message.clone())?;
+        serde_json::to_string(&ResponseFrame {
+            id: None,
+            queue_id: message.queue_id,
+            response,
+        })
+        .unwrap()
+    } else {
+        serde_json::to_string(message).unwrap()
+    };
+
+    Some(frame::encode(json, attachment))
 }
@@ -0,0 +1,142 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use futures_util::SinkExt;
+use log::{debug, info};
+use tokio::{sync::Mutex, time};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    protocol::{ApiError, Response, ResponseFrame, PROTOCOL_VERSION},
+    user_handler::UserDatabase,
+    util::PresenceInfo,
+    Session, SessionDb,
+};
+
+/// the presence of a user as `viewer` may see it, users that hide from the
+/// viewer look like they never were online
+pub async fn lookup(
+    session_db: &SessionDb,
+    user_db: &Arc<Mutex<UserDatabase>>,
+    username: &str,
+    viewer: &str,
+) -> Result<PresenceInfo, ApiError> {
+    Ok(visible_presence(session_db, user_db, username, viewer)
+        .await?
+        .unwrap_or_else(|| hidden(username)))
+}
+
+/// the presence of a user, `None` if they hide from the viewer
+async fn visible_presence(
+    session_db: &SessionDb,
+    user_db: &Arc<Mutex<UserDatabase>>,
+    username: &str,
+    viewer: &str,
+) -> Result<Option<PresenceInfo>, ApiError> {
+    let visible = user_db.lock().await.may_see_presence(username.to_string(), viewer.to_string())?;
+    if !visible {
+        return Ok(None);
+    }
+
+    let last_seen = user_db.lock().await.last_seen(username.to_string())?;
+    let online = session_db.lock().await.contains_key(username);
+
+    Ok(Some(PresenceInfo {
+        user: username.to_string(),
+        online,
+        last_seen,
+    }))
+}
+
+fn hidden(username: &str) -> PresenceInfo {
+    PresenceInfo {
+        user: username.to_string(),
+        online: false,
+        last_seen: None,
+    }
+}
+
+/// tells every device that subscribed to the user how they look now, called
+/// when they come online, go offline or change who may see them. Viewers the
+/// user hides from only hear about it once, right after the settings changed,
+/// so they can't tell when the user comes and goes.
+pub async fn broadcast(
+    session_db: &SessionDb,
+    user_db: &Arc<Mutex<UserDatabase>>,
+    username: &str,
+    settings_changed: bool,
+    send_timeout: Duration,
+) {
+    // the session lock is released before looking anything up or sending
+    let subscribers: Vec<(String, Session)> = session_db
+        .lock()
+        .await
+        .iter()
+        .flat_map(|(viewer, devices)| {
+            devices
+                .values()
+                .filter(|session| session.capabilities.contains("presence") && session.subscriptions.contains(username))
+                .map(move |session| (viewer.clone(), session.clone()))
+        })
+        .collect();
+
+    // every device of a viewer sees the same
+    let mut seen: HashMap<String, Option<PresenceInfo>> = HashMap::new();
+
+    for (viewer, session) in subscribers {
+        let presence = match seen.get(&viewer) {
+            Some(presence) => presence.clone(),
+            None => match visible_presence(session_db, user_db, username, &viewer).await {
+                Ok(presence) => {
+                    let presence = presence.or_else(|| settings_changed.then(|| hidden(username)));
+                    seen.insert(viewer.clone(), presence.clone());
+                    presence
+                }
+                Err(e) => {
+                    info!("couldnt look up the presence of {}: {}", username, e);
+                    return;
+                }
+            },
+        };
+        let Some(presence) = presence else {
+            continue;
+        };
+
+        let response = Response::Presence {
+            action: "presence".to_string(),
+            presence: vec![presence],
+        };
+        let json = if session.version >= PROTOCOL_VERSION {
+            serde_json::to_string(&ResponseFrame { id: None, queue_id: None, response }).unwrap()
+        } else {
+            serde_json::to_string(&response.into_legacy(&viewer)).unwrap()
+        };
+
+        let sent = time::timeout(send_timeout, async {
+            session.ws_write.lock().await.send(Message::Text(json)).await
+        })
+        .await;
+        match sent {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("couldnt send the presence of {} to {}: {}", username, viewer, e),
+            Err(_) => debug!("sending the presence of {} to {} timed out", username, viewer),
+        }
+    }
+}
+
+/// records when a device of the user disconnected and tells the subscribers
+/// if it was the last one
+pub async fn device_disconnected(
+    session_db: &SessionDb,
+    user_db: &Arc<Mutex<UserDatabase>>,
+    username: &str,
+    send_timeout: Duration,
+) {
+    let result = user_db.lock().await.touch_last_seen(username.to_string());
+    if let Err(e) = result {
+        info!("couldnt update the last seen time of {}: {}", username, e);
+    }
+
+    if !session_db.lock().await.contains_key(username) {
+        broadcast(session_db, user_db, username, false, send_timeout).await;
+    }
+}
@@ -0,0 +1,685 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::util::{
+    BlobInfo, GroupInfo, KeyBundle, KeyPairB64, MsgContent, MsgPayload, OpAuthPayload, PresenceInfo,
+    PresenceVisibility,
+};
+
+/// Version 1 is the original protocol where every frame is a `MsgPayload`
+/// and the action is a free-form string. Clients that don't say `hello` are
+/// served with it through the conversions below.
+pub const LEGACY_VERSION: u32 = 1;
+/// tagged requests and responses, see `Request` and `Response`
+pub const PROTOCOL_VERSION: u32 = 2;
+/// optional features a client can ask for in its `hello`
+pub const CAPABILITIES: &[&str] = &["multi_device", "groups", "binary_frames", "blobs", "presence"];
+
+/// every request `type` a version 2 client can send
+pub const ACTIONS: &[&str] = &[
+    "hello", "register", "login", "resume", "revoke", "logout", "fetch_bundle", "ack",
+    "rotate_prekey", "upload_onetime_keys", "count_onetime_keys", "create_group", "invite",
+    "leave", "kick", "create_blob", "upload_blob", "download_blob", "subscribe_presence",
+    "unsubscribe_presence", "last_seen", "presence_settings", "message",
+];
+
+/// What went wrong, these names are part of the protocol and never change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// the frame isn't valid JSON or misses fields of its request
+    MalformedFrame,
+    UnknownAction,
+    UnsupportedVersion,
+    /// the request is well formed but can't be done like this
+    InvalidRequest,
+    NotAuthenticated,
+    /// wrong username or password, which of the two isn't told
+    InvalidCredentials,
+    /// the resume or revoke token is unknown or expired
+    InvalidToken,
+    UserNotFound,
+    UsernameTaken,
+    /// the device logs in for the first time and has to bring a keybundle
+    UnknownDevice,
+    InvalidKeybundle,
+    NoKeybundle,
+    GroupNotFound,
+    NotGroupAdmin,
+    NotGroupMember,
+    AlreadyGroupMember,
+    BlobNotFound,
+    BlobIncomplete,
+    /// the upload has to continue at the offset in the error
+    BlobOffsetMismatch,
+    PayloadTooLarge,
+    /// a device of the recipient has too many unacknowledged messages, it
+    /// accepts more once it acknowledged some. Nobody got the message then.
+    QueueFull,
+    /// too many failed logins from this address or for this account, wait `retry_after`
+    RateLimited,
+    /// the account is locked after too many failed logins until `retry_after` passed
+    AccountLocked,
+    /// an admin disabled the account
+    AccountDisabled,
+    /// something failed on the server, trying again later may work
+    Internal,
+}
+
+impl ErrorCode {
+    /// whether the same request can succeed when it is sent again
+    pub fn retryable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::Internal
+                | ErrorCode::BlobOffsetMismatch
+                | ErrorCode::RateLimited
+                | ErrorCode::AccountLocked
+                | ErrorCode::QueueFull
+        )
+    }
+}
+
+/// an error the client gets to see
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// seconds until the request may be sent again
+    pub retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// plain string errors come from the database or the file system
+impl From<String> for ApiError {
+    fn from(message: String) -> Self {
+        ApiError::new(ErrorCode::Internal, message)
+    }
+}
+
+/// A frame sent by a version 2 client, e.g.
+/// `{"type": "login", "id": "1", "user": "alice", "password": "..."}`.
+/// The `id` is echoed in the answer so requests and responses can be matched.
+#[derive(Debug, serde::Deserialize)]
+pub struct RequestFrame {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Hello {
+        versions: Vec<u32>,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    Register {
+        user: String,
+        password: String,
+        #[serde(default)]
+        device: Option<String>,
+        keybundle: KeyBundle,
+    },
+    Login {
+        user: String,
+        password: String,
+        #[serde(default)]
+        device: Option<String>,
+        #[serde(default)]
+        keybundle: Option<KeyBundle>,
+    },
+    Resume {
+        token: String,
+    },
+    Revoke {
+        #[serde(default)]
+        token: Option<String>,
+    },
+    Logout,
+    FetchBundle {
+        user: String,
+    },
+    Ack {
+        #[serde(default)]
+        message_id: String,
+        /// the `queue_id` the message was delivered with, older clients only send the `message_id`
+        #[serde(default)]
+        queue_id: Option<i64>,
+    },
+    RotatePrekey {
+        prekey: KeyPairB64,
+        signature: KeyPairB64,
+    },
+    UploadOnetimeKeys {
+        keys: Vec<KeyPairB64>,
+    },
+    CountOnetimeKeys,
+    CreateGroup {
+        name: String,
+        #[serde(default)]
+        members: Vec<String>,
+    },
+    Invite {
+        group: String,
+        user: String,
+    },
+    Leave {
+        group: String,
+    },
+    Kick {
+        group: String,
+        user: String,
+    },
+    CreateBlob {
+        size: u64,
+        #[serde(default)]
+        recipients: Vec<String>,
+    },
+    /// `blob` is the blob id, `id` already correlates the request
+    UploadBlob {
+        blob: String,
+        offset: u64,
+    },
+    DownloadBlob {
+        blob: String,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default)]
+        length: u64,
+    },
+    SubscribePresence {
+        users: Vec<String>,
+    },
+    UnsubscribePresence {
+        users: Vec<String>,
+    },
+    LastSeen {
+        user: String,
+    },
+    PresenceSettings {
+        visibility: PresenceVisibility,
+    },
+    Message {
+        message_id: String,
+        recipient: String,
+        #[serde(default)]
+        timestamp: u64,
+        #[serde(default)]
+        content: Option<MsgContent>,
+    },
+}
+
+impl Request {
+    /// the action name used in answers and by legacy clients
+    pub fn action(&self) -> &'static str {
+        match self {
+            Request::Hello { .. } => "hello",
+            Request::Register { .. } => "register",
+            Request::Login { .. } => "login",
+            Request::Resume { .. } => "resume",
+            Request::Revoke { .. } => "revoke",
+            Request::Logout => "logout",
+            Request::FetchBundle { .. } => "fetch_bundle",
+            Request::Ack { .. } => "ack",
+            Request::RotatePrekey { .. } => "rotate_prekey",
+            Request::UploadOnetimeKeys { .. } => "upload_onetime_keys",
+            Request::CountOnetimeKeys => "count_onetime_keys",
+            Request::CreateGroup { .. } => "create_group",
+            Request::Invite { .. } => "invite",
+            Request::Leave { .. } => "leave",
+            Request::Kick { .. } => "kick",
+            Request::CreateBlob { .. } => "create_blob",
+            Request::UploadBlob { .. } => "upload_blob",
+            Request::DownloadBlob { .. } => "download_blob",
+            Request::SubscribePresence { .. } => "subscribe_presence",
+            Request::UnsubscribePresence { .. } => "unsubscribe_presence",
+            Request::LastSeen { .. } => "last_seen",
+            Request::PresenceSettings { .. } => "presence_settings",
+            Request::Message { .. } => "message",
+        }
+    }
+
+    /// everything except saying hello and getting a session needs one
+    pub fn requires_auth(&self) -> bool {
+        !matches!(
+            self,
+            Request::Hello { .. }
+                | Request::Register { .. }
+                | Request::Login { .. }
+                | Request::Resume { .. }
+                | Request::Logout
+        )
+    }
+
+    /// reads a version 1 frame
+    pub fn from_legacy(msg: MsgPayload) -> Result<Request, ApiError> {
+        let auth = match msg.auth {
+            Some(auth) if msg.content.is_none() => auth,
+            _ if !msg.recipient.is_empty() => {
+                return Ok(Request::Message {
+                    message_id: msg.message_id,
+                    recipient: msg.recipient,
+                    timestamp: msg.timestamp,
+                    content: msg.content,
+                })
+            }
+            _ => {
+                return Err(ApiError::new(
+                    ErrorCode::MalformedFrame,
+                    "frame has neither an action nor a recipient",
+                ))
+            }
+        };
+
+        let group = auth.group.clone().unwrap_or_default();
+        let blob = auth.blob.clone().unwrap_or_default();
+
+        let request = match auth.action.as_str() {
+            "login" => Request::Login {
+                user: auth.user,
+                password: auth.password,
+                device: auth.device,
+                keybundle: auth.keybundle,
+            },
+            "resume" => Request::Resume {
+                token: auth.token.unwrap_or_default(),
+            },
+            "revoke" => Request::Revoke { token: auth.token },
+            "register" => Request::Register {
+                user: auth.user,
+                password: auth.password,
+                device: auth.device,
+                keybundle: auth.keybundle.ok_or_else(no_keybundle)?,
+            },
+            "logout" => Request::Logout,
+            "fetch_bundle" => Request::FetchBundle { user: auth.user },
+            "ack" => Request::Ack {
+                message_id: msg.message_id,
+                queue_id: msg.queue_id,
+            },
+            "rotate_prekey" => {
+                let bundle = auth.keybundle.ok_or_else(no_keybundle)?;
+                Request::RotatePrekey {
+                    prekey: bundle.prekey,
+                    signature: bundle.signature,
+                }
+            }
+            "upload_onetime_keys" => Request::UploadOnetimeKeys {
+                keys: auth.onetime_keys.unwrap_or_default(),
+            },
+            "count_onetime_keys" => Request::CountOnetimeKeys,
+            "create_group" => Request::CreateGroup {
+                name: group.name,
+                members: group.members,
+            },
+            "invite" => Request::Invite {
+                group: group.id,
+                user: auth.user,
+            },
+            "leave" => Request::Leave { group: group.id },
+            "kick" => Request::Kick {
+                group: group.id,
+                user: auth.user,
+            },
+            "create_blob" => Request::CreateBlob {
+                size: blob.size,
+                recipients: blob.recipients,
+            },
+            "upload_blob" => Request::UploadBlob {
+                blob: blob.id,
+                offset: blob.offset,
+            },
+            "download_blob" => Request::DownloadBlob {
+                blob: blob.id,
+                offset: blob.offset,
+                length: blob.length,
+            },
+            "subscribe_presence" => Request::SubscribePresence {
+                users: presence_users(auth.presence),
+            },
+            "unsubscribe_presence" => Request::UnsubscribePresence {
+                users: presence_users(auth.presence),
+            },
+            "last_seen" => Request::LastSeen { user: auth.user },
+            "presence_settings" => Request::PresenceSettings {
+                visibility: auth
+                    .visibility
+                    .ok_or_else(|| ApiError::new(ErrorCode::InvalidRequest, "No visibility given"))?,
+            },
+            other => {
+                return Err(ApiError::new(
+                    ErrorCode::UnknownAction,
+                    format!("no such auth action {}", other),
+                ))
+            }
+        };
+
+        Ok(request)
+    }
+}
+
+/// A frame sent to a version 2 client, `id` is the one of the request it answers
+/// and left out for events like incoming messages.
+#[derive(Debug, serde::Serialize)]
+pub struct ResponseFrame {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// queued events carry the id to acknowledge them with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_id: Option<i64>,
+    #[serde(flatten)]
+    pub response: Response,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+    /// answer to `register`, `login` and `resume`
+    Authenticated {
+        action: String,
+        user: String,
+        device: String,
+        token: String,
+    },
+    Revoked,
+    Bundles {
+        user: String,
+        keybundles: Vec<KeyBundle>,
+    },
+    PrekeyRotated,
+    /// answer to `upload_onetime_keys` and `count_onetime_keys`
+    OnetimeKeys {
+        action: String,
+        count: u64,
+    },
+    /// answer to the group actions
+    Group {
+        action: String,
+        group: GroupInfo,
+    },
+    /// answer to the blob actions, downloads carry the data in a binary frame
+    Blob {
+        action: String,
+        blob: BlobInfo,
+    },
+    Error {
+        action: String,
+        code: ErrorCode,
+        message: String,
+        /// id of the request that failed, if it could be read
+        message_id: Option<String>,
+        retryable: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+        /// where a rejected upload has to continue
+        #[serde(skip_serializing_if = "Option::is_none")]
+        blob: Option<BlobInfo>,
+    },
+    /// answer to the presence actions, also pushed with the action `presence`
+    /// whenever a subscribed user comes online or goes offline
+    Presence {
+        action: String,
+        presence: Vec<PresenceInfo>,
+    },
+    PresenceSettings {
+        visibility: PresenceVisibility,
+    },
+    /// sent to every connection right before the server closes it
+    Shutdown {
+        message: String,
+    },
+    // everything below is pushed to the client and goes through the queue
+    Message {
+        message_id: String,
+        author: String,
+        recipient: String,
+        timestamp: u64,
+        content: Option<MsgContent>,
+    },
+    Delivered {
+        message_id: String,
+        user: String,
+        device: Option<String>,
+    },
+    /// the device of `user` lacks a capability the message needs, it got dropped
+    Undeliverable {
+        message_id: String,
+        user: String,
+        device: Option<String>,
+        message: String,
+    },
+    OnetimeKeysLow {
+        device: Option<String>,
+        count: u64,
+    },
+    GroupUpdate {
+        user: String,
+        group: GroupInfo,
+    },
+}
+
+impl Response {
+    /// the version 1 form of a response, which is also how events are queued
+    pub fn into_legacy(self, recipient: &str) -> MsgPayload {
+        match self {
+            Response::Hello { .. } => legacy(recipient, "hello", recipient, "Hello".to_string(), true),
+            Response::Authenticated { action, user, device, token } => {
+                let message = match action.as_str() {
+                    "register" => "Registration successful",
+                    "resume" => "Session resumed",
+                    _ => "Login successful",
+                };
+                let mut msg = legacy(&user, &action, &user, message.to_string(), true);
+                let auth = msg.auth.as_mut().unwrap();
+                auth.token = Some(token);
+                auth.device = Some(device);
+                msg
+            }
+            Response::Revoked => legacy(recipient, "revoke", recipient, "Token revoked".to_string(), true),
+            Response::Bundles { user, keybundles } => {
+                let mut msg = legacy(recipient, "fetch_bundle", &user, "fetched bundle".to_string(), true);
+                let auth = msg.auth.as_mut().unwrap();
+                auth.keybundle = keybundles.first().cloned();
+                auth.keybundles = Some(keybundles);
+                msg
+            }
+            Response::PrekeyRotated => {
+                legacy(recipient, "rotate_prekey", recipient, "Prekey rotated".to_string(), true)
+            }
+            Response::OnetimeKeys { action, count } => {
+                let message = format!("{} onetime keys left", count);
+                let mut msg = legacy(recipient, &action, recipient, message, true);
+                msg.auth.as_mut().unwrap().count = Some(count);
+                msg
+            }
+            Response::Group { action, group } => {
+                let mut msg = legacy(recipient, &action, recipient, "Group updated".to_string(), true);
+                msg.auth.as_mut().unwrap().group = Some(group);
+                msg
+            }
+            Response::Blob { action, blob } => {
+                let mut msg = legacy(recipient, &action, recipient, "ok".to_string(), true);
+                msg.auth.as_mut().unwrap().blob = Some(blob);
+                msg
+            }
+            Response::Error { action, code, message, blob, .. } => {
+                // version 1 clients show these messages as they are
+                let message = match action.as_str() {
+                    "login" => format!("Login failed: {}", message),
+                    "resume" => format!("Resume failed: {}", message),
+                    "revoke" => format!("Revoking token failed: {}", message),
+                    "register" => format!("Registration failed {}", message),
+                    "fetch_bundle" => format!("fetching bundle failed {}", message),
+                    "rotate_prekey" => format!("Rotating prekey failed {}", message),
+                    "upload_onetime_keys" | "count_onetime_keys" => {
+                        format!("Counting onetime keys failed {}", message)
+                    }
+                    "create_group" | "invite" | "leave" | "kick" => format!("Group action failed {}", message),
+                    "create_blob" | "upload_blob" | "download_blob" => format!("Blob action failed {}", message),
+                    _ => message,
+                };
+                let mut msg = legacy(recipient, &action, recipient, message, false);
+                let auth = msg.auth.as_mut().unwrap();
+                auth.error = Some(code);
+                auth.blob = blob;
+                msg
+            }
+            Response::Message { message_id, author, recipient, timestamp, content } => MsgPayload {
+                content,
+                timestamp,
+                auth: None,
+                message_id,
+                author,
+                recipient,
+                queue_id: None,
+            },
+            Response::Delivered { message_id, user, device } => {
+                let mut msg = legacy(recipient, "delivered", &user, "Message delivered".to_string(), true);
+                msg.message_id = message_id;
+                msg.auth.as_mut().unwrap().device = device;
+                msg
+            }
+            Response::Undeliverable { message_id, user, device, message } => {
+                let mut msg = legacy(recipient, "undeliverable", &user, message, false);
+                msg.message_id = message_id;
+                msg.auth.as_mut().unwrap().device = device;
+                msg
+            }
+            Response::Presence { action, presence } => {
+                let mut msg = legacy(recipient, &action, recipient, "ok".to_string(), true);
+                msg.auth.as_mut().unwrap().presence = Some(presence);
+                msg
+            }
+            Response::PresenceSettings { visibility } => {
+                let message = "Presence settings updated".to_string();
+                let mut msg = legacy(recipient, "presence_settings", recipient, message, true);
+                msg.auth.as_mut().unwrap().visibility = Some(visibility);
+                msg
+            }
+            Response::Shutdown { message } => legacy(recipient, "shutdown", recipient, message, true),
+            Response::OnetimeKeysLow { device, count } => {
+                let message = "Running low on onetime keys, please upload more".to_string();
+                let mut msg = legacy(recipient, "onetime_keys_low", recipient, message, true);
+                let auth = msg.auth.as_mut().unwrap();
+                auth.count = Some(count);
+                auth.device = device;
+                msg
+            }
+            Response::GroupUpdate { user, group } => {
+                let message = format!("{} changed the group", user);
+                let mut msg = legacy(recipient, "group_update", &user, message, true);
+                msg.auth.as_mut().unwrap().group = Some(group);
+                msg
+            }
+        }
+    }
+
+    /// turns a queued message back into the event it was created from,
+    /// `None` for anything that isn't an event
+    pub fn from_legacy(msg: MsgPayload) -> Option<Response> {
+        let auth = match msg.auth {
+            Some(auth) if msg.content.is_none() => auth,
+            _ => {
+                return Some(Response::Message {
+                    message_id: msg.message_id,
+                    author: msg.author,
+                    recipient: msg.recipient,
+                    timestamp: msg.timestamp,
+                    content: msg.content,
+                })
+            }
+        };
+
+        match auth.action.as_str() {
+            "delivered" => Some(Response::Delivered {
+                message_id: msg.message_id,
+                user: auth.user,
+                device: auth.device,
+            }),
+            "undeliverable" => Some(Response::Undeliverable {
+                message_id: msg.message_id,
+                user: auth.user,
+                device: auth.device,
+                message: auth.message,
+            }),
+            "onetime_keys_low" => Some(Response::OnetimeKeysLow {
+                device: auth.device,
+                count: auth.count.unwrap_or(0),
+            }),
+            "group_update" => Some(Response::GroupUpdate {
+                user: auth.user,
+                group: auth.group.unwrap_or_default(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// the users a version 1 presence request names
+fn presence_users(presence: Option<Vec<PresenceInfo>>) -> Vec<String> {
+    presence.unwrap_or_default().into_iter().map(|info| info.user).collect()
+}
+
+fn no_keybundle() -> ApiError {
+    ApiError::new(ErrorCode::MalformedFrame, "No keybundle given")
+}
+
+/// a version 1 answer from the server
+fn legacy(recipient: &str, action: &str, user: &str, message: String, success: bool) -> MsgPayload {
+    MsgPayload {
+        content: None,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs(),
+        auth: Some(OpAuthPayload {
+            message,
+            action: action.to_string(),
+            user: user.to_string(),
+            password: "".to_string(),
+            keybundle: None,
+            success: Some(success),
+            token: None,
+            onetime_keys: None,
+            count: None,
+            keybundles: None,
+            device: None,
+            group: None,
+            blob: None,
+            error: None,
+            presence: None,
+            visibility: None,
+        }),
+        message_id: uuid::Uuid::new_v4().to_string(),
+        author: "System".to_string(),
+        recipient: recipient.to_string(),
+        queue_id: None,
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::{IpAddr, Ipv6Addr},
+};
+
+use log::info;
+
+use crate::{
+    config::RateLimitConfig,
+    protocol::{ApiError, ErrorCode},
+};
+
+/// Failed logins of one IP address or account.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Failures {
+    pub count: u32,
+    pub last_failure_at: u64,
+}
+
+/// Counts failed logins per IP address so a client can't get around the
+/// per account limits by guessing at many accounts or reconnecting. The
+/// port is left out, every new connection gets a different one. IPv6
+/// clients usually get a whole /64, so they are counted per /64.
+///
+/// This only lives in memory, the account side is kept in the database.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    ips: HashMap<IpAddr, Failures>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            ips: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// checks whether the address may try to log in right now and counts the
+    /// attempt as failed right away, so logins running at the same time can't
+    /// all get past the backoff. `succeeded` takes it back again.
+    pub fn attempt(&mut self, ip: IpAddr, now: u64) -> Result<(), ApiError> {
+        // forgotten failures dont need to be remembered
+        let lockout = self.config.lockout_secs;
+        self.ips.retain(|_, failures| failures.last_failure_at + lockout > now);
+
+        let key = key(ip);
+        if let Some(failures) = self.ips.get(&key) {
+            self.check_backoff(*failures, now)?;
+        }
+
+        let failures = self.ips.entry(key).or_default();
+        failures.count += 1;
+        failures.last_failure_at = now;
+
+        if failures.count > self.config.free_attempts {
+            info!("{} failed to log in {} times in a row", key, failures.count);
+        }
+
+        Ok(())
+    }
+
+    /// takes back the failure `attempt` counted for a login that went through
+    pub fn succeeded(&mut self, ip: IpAddr) {
+        if let Entry::Occupied(mut entry) = self.ips.entry(key(ip)) {
+            entry.get_mut().count -= 1;
+            if entry.get().count == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    /// rejects an attempt that comes before the backoff of the earlier failures ran out
+    pub fn check_backoff(&self, failures: Failures, now: u64) -> Result<(), ApiError> {
+        let Some(wait) = self.backoff_secs(failures.count) else {
+            return Ok(());
+        };
+
+        let allowed_at = failures.last_failure_at + wait;
+        if now >= allowed_at {
+            return Ok(());
+        }
+
+        let retry_after = allowed_at - now;
+        Err(ApiError::new(
+            ErrorCode::RateLimited,
+            format!("Too many failed logins, try again in {} seconds", retry_after),
+        )
+        .retry_after(retry_after))
+    }
+
+    /// how long to wait after `count` failures, `None` while they are free
+    fn backoff_secs(&self, count: u32) -> Option<u64> {
+        let doublings = count.checked_sub(self.config.free_attempts)?;
+
+        let wait = self
+            .config
+            .backoff_base_secs
+            .checked_shl(doublings)
+            .filter(|wait| wait >> doublings == self.config.backoff_base_secs)
+            .unwrap_or(u64::MAX);
+
+        Some(wait.min(self.config.backoff_max_secs))
+    }
+}
+
+/// the address failures are counted under
+fn key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(backoff_base_secs: u64, backoff_max_secs: u64) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            free_attempts: 3,
+            backoff_base_secs,
+            backoff_max_secs,
+            ..RateLimitConfig::default()
+        })
+    }
+
+    #[test]
+    fn backoff_doubles_after_free_attempts() {
+        let limiter = limiter(2, 300);
+
+        assert_eq!(limiter.backoff_secs(2), None);
+        assert_eq!(limiter.backoff_secs(3), Some(2));
+        assert_eq!(limiter.backoff_secs(4), Some(4));
+        assert_eq!(limiter.backoff_secs(6), Some(16));
+        assert_eq!(limiter.backoff_secs(20), Some(300));
+    }
+
+    #[test]
+    fn backoff_overflow_is_capped() {
+        let uncapped = limiter(3, u64::MAX);
+
+        // shifting 3 left by 63 loses bits, by 64 and more isnt possible at all
+        assert_eq!(uncapped.backoff_secs(3 + 63), Some(u64::MAX));
+        assert_eq!(uncapped.backoff_secs(3 + 64), Some(u64::MAX));
+        assert_eq!(uncapped.backoff_secs(u32::MAX), Some(u64::MAX));
+
+        assert_eq!(limiter(3, 600).backoff_secs(u32::MAX), Some(600));
+    }
+
+    #[test]
+    fn attempts_count_before_they_fail() {
+        let mut limiter = limiter(2, 300);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(limiter.attempt(ip, 100).is_ok());
+        }
+        assert!(limiter.attempt(ip, 100).is_err());
+
+        limiter.succeeded(ip);
+        assert!(limiter.attempt(ip, 100).is_ok());
+        assert!(limiter.attempt(ip, 102).is_ok());
+    }
+
+    #[test]
+    fn ipv6_is_counted_per_64() {
+        let mut limiter = limiter(2, 300);
+
+        for i in 0..3 {
+            let ip: IpAddr = format!("2001:db8:0:1::{:x}", i + 1).parse().unwrap();
+            assert!(limiter.attempt(ip, 100).is_ok());
+        }
+        assert!(limiter.attempt("2001:db8:0:1:ffff::1".parse().unwrap(), 100).is_err());
+        assert!(limiter.attempt("2001:db8:0:2::1".parse().unwrap(), 100).is_ok());
+    }
+
+    #[test]
+    fn ipv4_mapped_is_counted_as_ipv4() {
+        let mut limiter = limiter(2, 300);
+
+        for _ in 0..3 {
+            assert!(limiter.attempt("192.0.2.1".parse().unwrap(), 100).is_ok());
+        }
+        assert!(limiter.attempt("::ffff:192.0.2.1".parse().unwrap(), 100).is_err());
+    }
+}
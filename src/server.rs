@@ -1,125 +1,172 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, fs, net::SocketAddr, sync::Arc, time::Duration};
 
 use log::info;
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    signal::unix::{signal, SignalKind},
+    sync::{watch, Mutex},
+    time,
 };
 
-use tokio_rustls::rustls::{Certificate, PrivateKey};
+use crate::{admin, blobs::BlobStore, metrics::{self, METRICS}, config::Config, node::CipherNode, tls::TlsStore, user_handler::UserDatabase, SessionDb};
 
-use crate::{node::CipherNode, user_handler::UserDatabase, util::MsgPayload};
-
-use std::{
-    env,
-    fs::{self, File},
-    hash::Hash,
-    io::{Error, Read},
-    time::{SystemTime, UNIX_EPOCH},
-};
-
-use futures_util::{
-    future,
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt, TryStreamExt,
-};
-use lazy_static::lazy_static;
-use rustls_pemfile::{certs, rsa_private_keys};
-use std::io::{self, BufReader};
-use std::net::ToSocketAddrs;
-use std::path::{Path, PathBuf};
-use tokio::io::{copy, sink, split, AsyncWriteExt};
-use tokio::time;
-use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use futures_util::future;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_tungstenite::WebSocketStream;
 
 pub struct CipherServer {
-    session_db: Arc<Mutex<HashMap<String, Arc<Mutex<CipherNode>>>>>,
+    config: Arc<Config>,
+    tls: TlsStore,
+    session_db: SessionDb,
     user_db: Arc<Mutex<UserDatabase>>,
-    msg_queue: Arc<Mutex<HashMap<String, Vec<MsgPayload>>>>,
-    listener: TcpListener,
+    blobs: BlobStore,
+    listeners: Vec<TcpListener>,
+    /// tells every connection to close once a shutdown signal arrived
+    shutdown: watch::Sender<bool>,
 }
 
 impl CipherServer {
-    pub async fn new(addr: String) -> Self {
-        let try_socket = TcpListener::bind(&addr).await;
-        let listener = try_socket.expect("Failed to bind");
-        info!("Listening on: {}", addr);
+    /// sets up everything the server needs, so broken certificates or
+    /// databases are reported at startup instead of on the first connection
+    pub async fn new(config: Config) -> Result<Self, String> {
+        let tls = TlsStore::load(&config.tls)?;
+
+        let user_db = UserDatabase::new(&config).await?;
+
+        let blobs = BlobStore::new(&config.blobs).await?;
+
+        let mut listeners = Vec::new();
+        for addr in &config.listen {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| format!("couldnt bind {}: {}", addr, e))?;
+            info!("Listening on: {}", addr);
+            listeners.push(listener);
+        }
 
-        let user_db: Arc<Mutex<UserDatabase>> = Arc::new(Mutex::new(UserDatabase::new().await));
+        tls.clone().watch();
+
+        let user_db: Arc<Mutex<UserDatabase>> = Arc::new(Mutex::new(user_db));
         let session_db = Arc::new(Mutex::new(HashMap::new()));
 
-        let msg_queue: Arc<Mutex<HashMap<String, Vec<MsgPayload>>>> =
-            Arc::new(Mutex::new(HashMap::new()));
+        blobs.clone().collect_garbage(user_db.clone());
+
+        if config.admin.enabled {
+            admin::serve(&config.admin, config.connection.send_timeout(), user_db.clone(), session_db.clone(), blobs.clone())?;
+        }
+
+        if config.metrics.enabled {
+            metrics::serve(&config.metrics, session_db.clone(), user_db.clone()).await;
+        }
 
-        Self {
+        Ok(Self {
+            config: Arc::new(config),
+            tls,
             session_db,
             user_db,
-            msg_queue,
-            listener,
+            blobs,
+            listeners,
+            shutdown: watch::channel(false).0,
+        })
+    }
+
+    /// accepts connections until SIGINT or SIGTERM, then shuts down
+    pub async fn process(&mut self) {
+        let listeners = std::mem::take(&mut self.listeners);
+        let accepting = future::join_all(listeners.iter().map(|listener| self.accept_loop(listener)));
+
+        tokio::select! {
+            _ = accepting => {}
+            signal = shutdown_signal() => info!("received {}, shutting down", signal),
         }
+
+        // closing the listeners makes new clients fail right away instead of
+        // waiting in the backlog until the process exits
+        drop(listeners);
+
+        self.shutdown().await;
     }
 
-    pub async fn process(&self) {
-        while let Ok((stream, addr)) = self.listener.accept().await {
+    /// asks every connection to close, waits for them up to the deadline
+    /// and makes sure the database is on disk before the process exits
+    async fn shutdown(&self) {
+        // no receiver is left once every connection ended
+        let _ = self.shutdown.send(true);
+
+        let deadline = Duration::from_secs(self.config.connection.shutdown_deadline_secs);
+        if time::timeout(deadline, self.shutdown.closed()).await.is_err() {
+            info!("some connections didnt close within {:?}, dropping them", deadline);
+        }
+
+        // holding the lock keeps the blob collector from starting a transaction while the process exits
+        let user_db = self.user_db.lock().await;
+        if let Err(e) = user_db.flush() {
+            info!("couldnt flush the database: {}", e);
+        }
+
+        if self.config.admin.enabled {
+            let _ = fs::remove_file(&self.config.admin.socket);
+        }
+
+        info!("shutdown complete");
+    }
+
+    async fn accept_loop(&self, listener: &TcpListener) {
+        while let Ok((stream, addr)) = listener.accept().await {
             // let node = CipherNode::new(stream, addr);
 
-            tokio::spawn(accept_connection(
-                stream,
+            let node = CipherNode::new(
                 addr,
+                self.config.clone(),
                 self.session_db.clone(),
                 self.user_db.clone(),
-                self.msg_queue.clone(),
-            ));
+                self.blobs.clone(),
+                self.shutdown.subscribe(),
+            );
+
+            tokio::spawn(accept_connection(stream, addr, self.tls.acceptor(), node));
 
             // node.cleanup();
         }
     }
 }
 
-pub async fn accept_connection(
-    stream: TcpStream,
-    addr: SocketAddr,
-    session_db: Arc<Mutex<HashMap<String, Arc<Mutex<CipherNode>>>>>,
-    user_db: Arc<Mutex<UserDatabase>>,
-    msg_queue: Arc<Mutex<HashMap<String, Vec<MsgPayload>>>>,
-) {
-    let addr = stream
-        .peer_addr()
-        .expect("connected streams should have a peer address");
-
-    let certs = load_certs(Path::new("localhost.crt")).unwrap();
-    let mut keys = load_keys(Path::new("localhost.key")).unwrap();
-
-    let tls_config = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, keys.remove(0))
-        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
-        .unwrap();
-
-    let tls_config = Arc::new(tls_config);
-    let acceptor = TlsAcceptor::from(tls_config);
-
-    let mut stream = acceptor.accept(stream).await.unwrap();
-
-    let ws_stream: WebSocketStream<TlsStream<TcpStream>> = tokio_tungstenite::accept_async(stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
-
-    let mut x = CipherNode::new(addr, session_db.clone(), user_db.clone(), msg_queue.clone());
+pub async fn accept_connection(stream: TcpStream, addr: SocketAddr, acceptor: TlsAcceptor, node: CipherNode) {
+    let stream = match acceptor.accept(stream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            METRICS.tls_handshake_failures.inc();
+            info!("TLS handshake with {} failed: {}", addr, e);
+            return;
+        }
+    };
+
+    let ws_stream: WebSocketStream<TlsStream<TcpStream>> = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            METRICS.websocket_handshake_failures.inc();
+            info!("WebSocket handshake with {} failed: {}", addr, e);
+            return;
+        }
+    };
 
-    x.process(ws_stream).await;
+    METRICS.connections.inc();
+    node.process(ws_stream).await;
+    METRICS.connections.dec();
 }
 
-fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
-    certs(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid cert"))
-        .map(|mut certs| certs.drain(..).map(Certificate).collect())
-}
+/// resolves with the name of the first SIGINT or SIGTERM
+async fn shutdown_signal() -> &'static str {
+    let (mut interrupt, mut terminate) = match (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) {
+        (Ok(interrupt), Ok(terminate)) => (interrupt, terminate),
+        (Err(e), _) | (_, Err(e)) => {
+            info!("couldnt listen for shutdown signals: {}", e);
+            return future::pending().await;
+        }
+    };
 
-fn load_keys(path: &Path) -> io::Result<Vec<PrivateKey>> {
-    rsa_private_keys(&mut BufReader::new(File::open(path)?))
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid key"))
-        .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
+    tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
 }
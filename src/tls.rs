@@ -0,0 +1,150 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+use rustls_pemfile::{certs, Item};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time,
+};
+use tokio_rustls::{
+    rustls::{self, Certificate, PrivateKey},
+    TlsAcceptor,
+};
+
+use crate::config::TlsConfig;
+
+/// how often the certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Holds the current `TlsAcceptor` so it only has to be built once instead of
+/// for every connection. Reloading swaps it atomically, connections that are
+/// already established keep using the config they were accepted with.
+#[derive(Clone)]
+pub struct TlsStore {
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl TlsStore {
+    pub fn load(config: &TlsConfig) -> Result<Self, String> {
+        let acceptor = build_acceptor(&config.cert, &config.key)?;
+
+        Ok(Self {
+            acceptor: Arc::new(RwLock::new(acceptor)),
+            cert: config.cert.clone(),
+            key: config.key.clone(),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    /// rebuilds the acceptor from disk, a broken certificate or key is
+    /// logged and the previous acceptor stays in use
+    pub fn reload(&self) {
+        match build_acceptor(&self.cert, &self.key) {
+            Ok(acceptor) => {
+                *self.acceptor.write().unwrap() = acceptor;
+                info!("reloaded TLS certificate from {}", self.cert.display());
+            }
+            Err(e) => {
+                warn!("couldnt reload TLS certificate, keeping the old one: {}", e);
+            }
+        }
+    }
+
+    /// reloads the certificate on SIGHUP and whenever the files change on disk
+    pub fn watch(self) {
+        tokio::spawn(async move {
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("couldnt listen for SIGHUP: {}", e);
+                    return;
+                }
+            };
+            let mut interval = time::interval(WATCH_INTERVAL);
+            let mut last_modified = self.modified();
+
+            loop {
+                tokio::select! {
+                    _ = hangup.recv() => {
+                        info!("received SIGHUP");
+                        last_modified = self.modified();
+                        self.reload();
+                    }
+                    _ = interval.tick() => {
+                        let modified = self.modified();
+                        if modified != last_modified {
+                            last_modified = modified;
+                            self.reload();
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert), modified(&self.key))
+    }
+}
+
+fn build_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, String> {
+    let certs = load_certs(cert)?;
+    let key_der = load_key(key)?;
+
+    let tls_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key_der)
+        .map_err(|e| format!("{} can't be used with {}: {}", key.display(), cert.display(), e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("couldnt open {}: {}", path.display(), e))?;
+
+    let certs = certs(&mut BufReader::new(file))
+        .map_err(|e| format!("couldnt parse {}: {}", path.display(), e))?;
+
+    if certs.is_empty() {
+        return Err(format!("{} contains no PEM certificate", path.display()));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// reads the first private key of the file, which may be PKCS#8 (RSA, ECDSA
+/// or Ed25519), PKCS#1 RSA or SEC1 EC encoded
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    let file = File::open(path).map_err(|e| format!("couldnt open {}: {}", path.display(), e))?;
+    let mut reader = BufReader::new(file);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| format!("couldnt parse {}: {}", path.display(), e))?
+        {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => {
+                return Ok(PrivateKey(key));
+            }
+            Some(_) => continue,
+            None => {
+                return Err(format!(
+                    "{} contains no PEM private key (PKCS#8, RSA or EC)",
+                    path.display()
+                ))
+            }
+        }
+    }
+}
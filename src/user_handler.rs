@@ -1,213 +1,1890 @@
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-
-use futures_util::stream::SplitSink;
-use log::{debug, info};
-use rusqlite::{params, Connection};
-use tokio::net::TcpStream;
-use tokio_rustls::server::TlsStream;
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::WebSocketStream;
-use uuid::Uuid;
-
-use sha256::digest;
-
-use crate::util::{KeyBundle, KeyPairB64};
-
-pub struct UserDatabase {
-    conn: Arc<Mutex<Connection>>,
-}
-
-impl UserDatabase {
-    pub async fn new() -> Self {
-        let conn = create_table_it_not_exist().await;
-
-        UserDatabase {
-            conn: Arc::new(Mutex::new(conn)),
-        }
-    }
-
-    pub fn register_user(
-        &self,
-        username: String,
-        password: String,
-        keybundle: KeyBundle,
-    ) -> Result<Uuid, String> {
-        let uuid = Uuid::new_v4();
-
-        let password = digest(password);
-
-        let query = format!("INSERT INTO users(name, password, token) VALUES ('{username}', '{password}', '{uuid}')");
-        let conn = self.conn.lock().unwrap();
-
-        match conn.execute(&query, []) {
-            Ok(_) => {
-                info!("successfully registered user to users!");
-            }
-            Err(e) => {
-                println!("Error {}", e);
-                return Err("Couldnt register User".to_string());
-            }
-        }
-
-        let mut stmt = conn
-            .prepare("SELECT user_id FROM users WHERE name=?1")
-            .unwrap();
-        let id: Option<i32> = stmt.query_row(params![username], |row| row.get(0)).unwrap();
-
-        let query = format!(
-            "INSERT INTO keybundles(identity, prekey, signature, user_id)
-         VALUES ('{}', '{}', '{}', '{}')",
-            keybundle.identity.public,
-            keybundle.prekey.public,
-            keybundle.signature.public,
-            id.unwrap()
-        );
-
-        match conn.execute(&query, []) {
-            Ok(_) => {
-                debug!("successfully registered bundle!");
-            }
-            Err(e) => {
-                println!("Error {}", e);
-                return Err("Couldnt register keybundle".to_string());
-            }
-        }
-
-        let mut stmt = conn
-            .prepare("SELECT bundle_id FROM keybundles WHERE user_id=?1")
-            .unwrap();
-        let bundle_id: Option<i32> = stmt.query_row(params![id], |row| row.get(0)).unwrap();
-
-        let mut stmt = conn
-            .prepare("INSERT INTO one_time_keys(key, bundle_id) VALUES (?, ?)")
-            .unwrap();
-
-        for otk in keybundle.onetime_keys {
-            stmt.execute(params![otk.public, bundle_id]).unwrap();
-        }
-
-        Ok(uuid)
-    }
-
-    pub fn login(&self, username: String, password: String) -> Result<Uuid, String> {
-        let uuid = Uuid::new_v4();
-
-        let password = digest(password);
-
-        let query = format!("UPDATE users SET token = '{uuid}' WHERE name = '{username}' AND password = '{password}'");
-        debug!("{}", query.clone());
-
-        let conn = self.conn.lock().unwrap();
-
-        let num = match conn.execute(&query, []) {
-            Ok(num) => num,
-            Err(e) => 0,
-        };
-
-        if num > 0 {
-            println!("success!");
-            Ok(uuid)
-        } else {
-            Err("Couldnt find User".to_string())
-        }
-    }
-
-    pub fn user_exists(&self, username: String) -> bool{
-
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT name FROM users WHERE name = ?").unwrap();
-
-        let rows: Vec<Result<String, rusqlite::Error>> = stmt.query_map(params![username], |row| {
-            Ok(row.get(0).unwrap())
-        }).unwrap().collect();
-        
-
-        if rows.len() > 0{
-            return true;
-        }
-
-        false
-    }
-
-    pub fn fetch_bundle(&self, username: String) -> Result<KeyBundle, String> {
-        let conn = self.conn.lock().unwrap();
-
-        let mut stmt = conn.prepare("SELECT identity, prekey, signature, key FROM keybundles a
-                                                        JOIN one_time_keys b ON a.bundle_id = b.bundle_id
-                                                        WHERE a.user_id = (SELECT user_id FROM users where name = ?)
-                                                        LIMIT 1;").unwrap();
-
-        let key_bundles: Vec<Result<KeyBundle, rusqlite::Error>> = stmt
-            .query_map(params![username], |row| {
-                Ok(KeyBundle {
-                    identity: KeyPairB64 {
-                        public: row.get(0)?,
-                        private: None,
-                    },
-                    prekey: KeyPairB64 {
-                        public: row.get(1)?,
-                        private: None,
-                    },
-                    signature: KeyPairB64 {
-                        public: row.get(2)?,
-                        private: None,
-                    },
-                    onetime_keys: vec![KeyPairB64 {
-                        public: row.get(3)?,
-                        private: None,
-                    }],
-                    ephemeral_key: None,
-                })
-            })
-            .unwrap()
-            .collect();
-
-        let key_bundle = key_bundles.get(0);
-        let key_bundle = key_bundle.unwrap().as_ref().unwrap().clone();
-
-        let mut stmt = conn.prepare("DELETE FROM one_time_keys WHERE key = ?").unwrap();
-        stmt.execute(params![key_bundle.onetime_keys.get(0).unwrap().public]).unwrap();
-
-        info!("deleted onetime key: {}", key_bundle.onetime_keys.get(0).unwrap().public);
-
-        Ok(key_bundle)
-    }
-}
-
-async fn create_table_it_not_exist() -> Connection {
-    if (Path::new("test.db").exists()) {
-        let connection = Connection::open("test.db").unwrap();
-        connection
-    } else {
-        let connection = Connection::open("test.db").unwrap();
-
-        let query = "
-        CREATE TABLE users (
-            user_id INTEGER PRIMARY KEY,
-            name TEXT UNIQUE NOT NULL,
-            password TEXT NOT NULL,
-            token TEXT UNIQUE NOT NULL
-        );
-        CREATE TABLE keybundles (
-            bundle_id INTEGER PRIMARY KEY,
-            identity TEXT NOT NULL,
-            prekey TEXT NOT NULL,
-            signature TEXT NOT NULL,
-            user_id      INTEGER NOT NULL,
-            FOREIGN KEY (user_id)
-                REFERENCES users (user_id) 
-        );
-        CREATE TABLE one_time_keys (
-            key TEXT NOT NULL,
-            bundle_id INTEGER NOT NULL,
-            FOREIGN KEY (bundle_id)
-                REFERENCES keybundles (bundle_id)
-        );
-    ";
-        connection.execute_batch(query).unwrap();
-
-        connection
-    }
-}
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, ToSql};
+use uuid::Uuid;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use sha256::digest;
+
+use crate::config::Config;
+use crate::keys;
+use crate::metrics::METRICS;
+use crate::migrations;
+use crate::protocol::{ApiError, ErrorCode};
+use crate::rate_limit::{Failures, RateLimiter};
+use crate::util::{GroupInfo, KeyBundle, KeyPairB64, MsgPayload, PresenceVisibility, PreviousPrekey};
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> Self {
+        ApiError::new(ErrorCode::Internal, format!("Database error: {}", e))
+    }
+}
+
+impl ToSql for PresenceVisibility {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(match self {
+            PresenceVisibility::Everyone => "everyone",
+            PresenceVisibility::Groups => "groups",
+            PresenceVisibility::Nobody => "nobody",
+        }))
+    }
+}
+
+impl FromSql for PresenceVisibility {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "everyone" => Ok(PresenceVisibility::Everyone),
+            "groups" => Ok(PresenceVisibility::Groups),
+            "nobody" => Ok(PresenceVisibility::Nobody),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// a message waiting in the queue together with the attachment of its binary frame
+pub struct QueuedMessage {
+    pub message: MsgPayload,
+    pub attachment: Option<Vec<u8>>,
+}
+
+/// what the database knows about a blob, the bytes themselves are in the `BlobStore`
+pub struct BlobMeta {
+    pub owner: String,
+    pub size: u64,
+    pub complete: bool,
+}
+
+/// an account as listed by the admin commands
+pub struct UserSummary {
+    pub name: String,
+    pub devices: u64,
+    pub queued: u64,
+    pub disabled: bool,
+    pub locked_until: u64,
+}
+
+/// a device of an account with its key bundle as shown by the admin commands
+pub struct DeviceSummary {
+    pub device: String,
+    pub identity: String,
+    pub prekey: String,
+    pub prekey_created_at: u64,
+    pub onetime_keys: u64,
+    pub queued: u64,
+}
+
+/// a queued message without its encrypted content
+pub struct QueueEntry {
+    pub device: String,
+    pub message_id: String,
+    pub author: String,
+    pub queued_at: u64,
+    pub size: u64,
+}
+
+const USER_SUMMARY: &str = "SELECT a.name,
+        (SELECT COUNT(*) FROM keybundles b WHERE b.user_id = a.user_id),
+        (SELECT COUNT(*) FROM message_queue c WHERE c.user_id = a.user_id),
+        a.disabled,
+        COALESCE((SELECT locked_until FROM login_failures d WHERE d.user_id = a.user_id), 0)
+    FROM users a";
+
+/// an account found by `UserDatabase::begin_login` whose password still has to be checked
+pub struct LoginAttempt {
+    username: String,
+    user_id: i32,
+    pub password_hash: String,
+    disabled: bool,
+    ip: IpAddr,
+    /// whether counting this attempt as failed locked the account
+    locks: bool,
+}
+
+/// outcome of `check_password`
+pub enum PasswordCheck {
+    Valid,
+    Invalid,
+    /// a sha256 digest from before argon2 matched, this argon2 hash replaces it
+    Upgrade(String),
+}
+
+/// how long a session token can be used to resume before it expires
+const TOKEN_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+pub struct UserDatabase {
+    conn: Arc<Mutex<Connection>>,
+    max_queued_messages: usize,
+    prekey_grace_period: u64,
+    prekey_max_age: u64,
+    login_limiter: RateLimiter,
+}
+
+impl UserDatabase {
+    pub async fn new(config: &Config) -> Result<Self, String> {
+        let conn = open_database(&config.database.path).await?;
+
+        Ok(UserDatabase {
+            conn: Arc::new(Mutex::new(conn)),
+            max_queued_messages: config.queue.max_messages_per_device,
+            prekey_grace_period: config.keys.prekey_grace_period_secs,
+            prekey_max_age: config.keys.prekey_max_age_secs,
+            login_limiter: RateLimiter::new(config.rate_limit.clone()),
+        })
+    }
+
+    pub fn register_user(
+        &self,
+        username: String,
+        password_hash: String,
+        device: String,
+        keybundle: KeyBundle,
+    ) -> Result<Uuid, ApiError> {
+        keys::verify_bundle(&keybundle).map_err(invalid_keybundle)?;
+
+        let conn = self.conn.lock().unwrap();
+
+        match conn.execute(
+            "INSERT INTO users(name, password) VALUES (?1, ?2)",
+            params![username, password_hash],
+        ) {
+            Ok(_) => {
+                info!("successfully registered user to users!");
+            }
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == rusqlite::ErrorCode::ConstraintViolation => {
+                return Err(ApiError::new(ErrorCode::UsernameTaken, format!("{} is already taken", username)));
+            }
+            Err(e) => {
+                error!("couldnt register {}: {}", username, e);
+                return Err(ApiError::new(ErrorCode::Internal, "Couldnt register User"));
+            }
+        }
+
+        let mut stmt = conn
+            .prepare("SELECT user_id FROM users WHERE name=?1")
+            .unwrap();
+        let id: i32 = stmt.query_row(params![username], |row| row.get(0)).unwrap();
+
+        insert_bundle(&conn, id, &device, &keybundle)?;
+
+        Ok(issue_token(&conn, id, &device)?)
+    }
+
+    /// looks up the account for a login and checks that it isn't locked. The
+    /// password is checked with `check_password` outside of the database lock
+    /// and the login completed by `finish_login`.
+    ///
+    /// The attempt already counts as failed here, logins running at the same
+    /// time would all get past the lockout otherwise. `finish_login` clears it
+    /// again when the password was right.
+    pub fn begin_login(&mut self, username: String, ip: IpAddr) -> Result<LoginAttempt, ApiError> {
+        let now = now();
+        self.login_limiter.attempt(ip, now)?;
+
+        let conn = self.conn.lock().unwrap();
+
+        let row: Option<(i32, String, bool)> = conn
+            .query_row(
+                "SELECT user_id, password, disabled FROM users WHERE name = ?1",
+                params![username],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .ok();
+
+        let (user_id, password_hash, disabled) = match row {
+            Some(v) => v,
+            None => return Err(invalid_credentials()),
+        };
+
+        check_account_lock(&conn, user_id, now, &self.login_limiter)?;
+        let locks = record_login_failure(&conn, user_id, now, &self.login_limiter)?;
+
+        Ok(LoginAttempt {
+            username,
+            user_id,
+            password_hash,
+            disabled,
+            ip,
+            locks,
+        })
+    }
+
+    /// issues a token for the device once the password was checked, a device
+    /// that isn't known yet gets added with the given keybundle. Failed attempts
+    /// slow down further ones from the same address and for the same account.
+    pub fn finish_login(
+        &mut self,
+        attempt: LoginAttempt,
+        check: PasswordCheck,
+        device: String,
+        keybundle: Option<KeyBundle>,
+    ) -> Result<Uuid, ApiError> {
+        let LoginAttempt { username, user_id: id, disabled, ip, locks, .. } = attempt;
+
+        let conn = self.conn.lock().unwrap();
+
+        let valid = match check {
+            PasswordCheck::Valid => true,
+            PasswordCheck::Invalid => false,
+            PasswordCheck::Upgrade(password) => {
+                info!("upgrading password hash of {}", username);
+                conn.execute(
+                    "UPDATE users SET password = ?1 WHERE user_id = ?2",
+                    params![password, id],
+                )
+                .map_err(|e| e.to_string())?;
+                true
+            }
+        };
+
+        if !valid {
+            if locks {
+                info!("locked {} after too many failed logins", username);
+                return Err(account_locked(self.login_limiter.config().lockout_secs));
+            }
+            return Err(invalid_credentials());
+        }
+
+        // this also takes back the failures begin_login counted
+        conn.execute("DELETE FROM login_failures WHERE user_id = ?", params![id])?;
+        self.login_limiter.succeeded(ip);
+
+        if disabled {
+            return Err(account_disabled());
+        }
+
+        let known: bool = conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM keybundles WHERE user_id = ?1 AND device_id = ?2)",
+                params![id, device],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+
+        if !known {
+            let keybundle = keybundle.ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::UnknownDevice,
+                    format!("Unknown device {}, a keybundle is needed to add it", device),
+                )
+            })?;
+            keys::verify_bundle(&keybundle).map_err(invalid_keybundle)?;
+
+            insert_bundle(&conn, id, &device, &keybundle)?;
+            info!("added device {} to {}", device, username);
+        }
+
+        debug!("{} logged in on {}", username, device);
+        Ok(issue_token(&conn, id, &device)?)
+    }
+
+    /// exchanges a still valid token for a fresh one, the old token
+    /// can't be used again afterwards
+    pub fn resume(&self, token: String) -> Result<(String, String, Uuid), ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        let row: Option<(i32, String, String)> = conn
+            .query_row(
+                "SELECT a.user_id, b.name, a.device_id FROM tokens a
+                 JOIN users b ON a.user_id = b.user_id
+                 WHERE a.token_hash = ?1 AND a.expires_at > ?2 AND b.disabled = 0",
+                params![token_hash(&token), now()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .ok();
+
+        let (id, username, device) = match row {
+            Some(v) => v,
+            None => return Err(ApiError::new(ErrorCode::InvalidToken, "Invalid or expired token")),
+        };
+
+        conn.execute("DELETE FROM tokens WHERE token_hash = ?", params![token_hash(&token)])
+            .map_err(|e| e.to_string())?;
+
+        let token = issue_token(&conn, id, &device)?;
+
+        Ok((username, device, token))
+    }
+
+    /// revokes a single token of the user
+    pub fn revoke_token(&self, username: String, token: String) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        match conn.execute(
+            "DELETE FROM tokens WHERE token_hash = ?1
+             AND user_id = (SELECT user_id FROM users WHERE name = ?2)",
+            params![token_hash(&token), username],
+        ) {
+            Ok(0) => Err(ApiError::new(ErrorCode::InvalidToken, "No such token")),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// revokes every token of the user, signing out all other sessions
+    pub fn revoke_tokens(&self, username: String) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            "DELETE FROM tokens WHERE user_id = (SELECT user_id FROM users WHERE name = ?)",
+            params![username],
+        )
+        .map(|_| ())
+        .map_err(ApiError::from)
+    }
+
+    pub fn user_exists(&self, username: String) -> bool{
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name FROM users WHERE name = ?").unwrap();
+
+        let rows: Vec<Result<String, rusqlite::Error>> = stmt.query_map(params![username], |row| {
+            Ok(row.get(0).unwrap())
+        }).unwrap().collect();
+        
+
+        !rows.is_empty()
+    }
+
+    /// every device of a user that registered a keybundle
+    pub fn devices(&self, username: String) -> Result<Vec<String>, String> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT device_id FROM keybundles
+                 WHERE user_id = (SELECT user_id FROM users WHERE name = ?)
+                 ORDER BY bundle_id ASC",
+            )
+            .map_err(|e| e.to_string())?;
+
+        let devices = stmt
+            .query_map(params![username], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+
+        Ok(devices)
+    }
+
+    /// returns the bundle of every device of a user and consumes one onetime
+    /// key of each, once those are used up the bundle comes without one as X3DH allows
+    pub fn fetch_bundles(&self, username: String) -> Result<Vec<KeyBundle>, ApiError> {
+        let mut conn = self.conn.lock().unwrap();
+
+        let tx = conn.transaction()?;
+
+        let user_id: i32 = tx
+            .query_row("SELECT user_id FROM users WHERE name = ?", params![username], |row| row.get(0))
+            .optional()?
+            .ok_or_else(|| user_not_found(&username))?;
+
+        let mut bundles: Vec<(i32, KeyBundle)> = {
+            let mut stmt = tx.prepare(
+                "SELECT bundle_id, identity, prekey, signature, prekey_created_at, device_id
+                 FROM keybundles WHERE user_id = ?
+                 ORDER BY bundle_id ASC",
+            )?;
+
+            let rows = stmt.query_map(params![user_id], |row| {
+                Ok((
+                    row.get(0)?,
+                    KeyBundle {
+                        identity: KeyPairB64 {
+                            public: row.get(1)?,
+                            private: None,
+                        },
+                        prekey: KeyPairB64 {
+                            public: row.get(2)?,
+                            private: None,
+                        },
+                        signature: KeyPairB64 {
+                            public: row.get(3)?,
+                            private: None,
+                        },
+                        onetime_keys: Vec::new(),
+                        ephemeral_key: None,
+                        prekey_created_at: Some(row.get(4)?),
+                        device: Some(row.get(5)?),
+                        previous_prekeys: Vec::new(),
+                    },
+                ))
+            })?;
+
+            rows.collect::<Result<_, _>>()?
+        };
+
+        if bundles.is_empty() {
+            return Err(ApiError::new(ErrorCode::NoKeybundle, "User has no keybundle"));
+        }
+
+        for (bundle_id, key_bundle) in bundles.iter_mut() {
+            let device = key_bundle.device.clone().unwrap_or_default();
+
+            let onetime_key: Option<(i64, String)> = tx
+                .query_row(
+                    "SELECT rowid, key FROM one_time_keys WHERE bundle_id = ? LIMIT 1",
+                    params![*bundle_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()?;
+
+            match onetime_key {
+                Some((rowid, key)) => {
+                    tx.execute("DELETE FROM one_time_keys WHERE rowid = ?", params![rowid])?;
+                    info!("deleted onetime key: {}", key);
+
+                    key_bundle.onetime_keys.push(KeyPairB64 {
+                        public: key,
+                        private: None,
+                    });
+                }
+                None => {
+                    METRICS.onetime_keys_exhausted.inc();
+                    info!(
+                        "{} ({}) has no onetime keys left, handing out bundle without one",
+                        username, device
+                    );
+                }
+            }
+
+            if key_bundle.prekey_created_at.unwrap_or(0) + self.prekey_max_age < now() {
+                info!("signed prekey of {} ({}) is stale and should be rotated", username, device);
+            }
+
+            let mut stmt = tx.prepare(
+                "SELECT prekey, signature, retired_at + ?2 FROM previous_prekeys
+                 WHERE bundle_id = ?1 AND retired_at + ?2 > ?3
+                 ORDER BY retired_at DESC",
+            )?;
+            let previous = stmt.query_map(params![*bundle_id, self.prekey_grace_period, now()], |row| {
+                Ok(PreviousPrekey {
+                    prekey: KeyPairB64 {
+                        public: row.get(0)?,
+                        private: None,
+                    },
+                    signature: KeyPairB64 {
+                        public: row.get(1)?,
+                        private: None,
+                    },
+                    expires_at: row.get(2)?,
+                })
+            })?;
+            key_bundle.previous_prekeys = previous.collect::<Result<_, _>>()?;
+        }
+
+        tx.commit()?;
+
+        Ok(bundles.into_iter().map(|(_, bundle)| bundle).collect())
+    }
+
+    /// replaces the signed prekey of a user, the previous one is kept for
+    /// the grace period so sessions started with it can still be completed
+    pub fn rotate_prekey(
+        &self,
+        username: String,
+        device: String,
+        prekey: KeyPairB64,
+        signature: KeyPairB64,
+    ) -> Result<(), ApiError> {
+        let mut conn = self.conn.lock().unwrap();
+        let now = now();
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        let (bundle_id, identity): (i32, String) = tx
+            .query_row(
+                "SELECT bundle_id, identity FROM keybundles
+                 WHERE user_id = (SELECT user_id FROM users WHERE name = ?1) AND device_id = ?2",
+                params![username, device],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|_| ApiError::new(ErrorCode::NoKeybundle, "Couldnt find keybundle"))?;
+
+        let identity = KeyPairB64 { public: identity, private: None };
+        keys::verify_signed_prekey(&identity, &prekey, &signature)
+            .map_err(|e| ApiError::new(ErrorCode::InvalidKeybundle, format!("Invalid prekey: {}", e)))?;
+
+        tx.execute(
+            "INSERT INTO previous_prekeys(bundle_id, prekey, signature, created_at, retired_at)
+             SELECT bundle_id, prekey, signature, prekey_created_at, ?2 FROM keybundles
+             WHERE bundle_id = ?1",
+            params![bundle_id, now],
+        )
+        .map_err(|e| e.to_string())?;
+
+        tx.execute(
+            "UPDATE keybundles SET prekey = ?1, signature = ?2, prekey_created_at = ?3
+             WHERE bundle_id = ?4",
+            params![prekey.public, signature.public, now, bundle_id],
+        )
+        .map_err(|e| e.to_string())?;
+
+        tx.execute(
+            "DELETE FROM previous_prekeys WHERE retired_at < ?",
+            params![now.saturating_sub(self.prekey_grace_period)],
+        )
+        .map_err(|e| e.to_string())?;
+
+        tx.commit().map_err(|e| e.to_string())?;
+
+        info!("rotated signed prekey of {} ({})", username, device);
+
+        Ok(())
+    }
+
+    /// adds onetime keys to the bundle of a device and returns how many are available now
+    pub fn upload_onetime_keys(
+        &self,
+        username: String,
+        device: String,
+        keys: Vec<KeyPairB64>,
+    ) -> Result<u64, ApiError> {
+        keys::verify_onetime_keys(&keys)
+            .map_err(|e| ApiError::new(ErrorCode::InvalidKeybundle, format!("Invalid onetime keys: {}", e)))?;
+
+        let mut conn = self.conn.lock().unwrap();
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        let bundle_id: i32 = tx
+            .query_row(
+                "SELECT bundle_id FROM keybundles
+                 WHERE user_id = (SELECT user_id FROM users WHERE name = ?1) AND device_id = ?2",
+                params![username, device],
+                |row| row.get(0),
+            )
+            .map_err(|_| ApiError::new(ErrorCode::NoKeybundle, "Couldnt find keybundle"))?;
+
+        {
+            let mut stmt = tx
+                .prepare("INSERT INTO one_time_keys(key, bundle_id) VALUES (?, ?)")
+                .map_err(|e| e.to_string())?;
+
+            for otk in &keys {
+                stmt.execute(params![otk.public, bundle_id])
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+
+        // the device gets asked again once these run low
+        tx.execute("UPDATE keybundles SET onetime_keys_notified = 0 WHERE bundle_id = ?", params![bundle_id])
+            .map_err(|e| e.to_string())?;
+
+        tx.commit().map_err(|e| e.to_string())?;
+
+        info!("{} ({}) uploaded {} onetime keys", username, device, keys.len());
+
+        Ok(count_onetime_keys(&conn, &username, &device)?)
+    }
+
+    /// how many unused onetime keys a device has left
+    pub fn count_onetime_keys(&self, username: String, device: String) -> Result<u64, ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        Ok(count_onetime_keys(&conn, &username, &device)?)
+    }
+
+    /// how many onetime keys a device has left if they dropped below the
+    /// watermark and it wasn't asked for more yet. It is asked once until
+    /// it uploads new keys.
+    pub fn claim_onetime_keys_notice(&self, username: String, device: String, watermark: u64) -> Result<Option<u64>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        let remaining = count_onetime_keys(&conn, &username, &device)?;
+        if remaining >= watermark {
+            return Ok(None);
+        }
+
+        let claimed = conn.execute(
+            "UPDATE keybundles SET onetime_keys_notified = 1
+             WHERE user_id = (SELECT user_id FROM users WHERE name = ?1) AND device_id = ?2
+             AND onetime_keys_notified = 0",
+            params![username, device],
+        )?;
+
+        Ok((claimed > 0).then_some(remaining))
+    }
+
+    /// queues a message for every given device of a recipient and returns
+    /// the queue ids in the same order. When one of the queues is full nothing
+    /// gets queued at all, so a retry of the sender doesn't deliver twice.
+    pub fn enqueue_message(
+        &self,
+        targets: &[(String, String)],
+        message: &MsgPayload,
+        attachment: Option<&[u8]>,
+    ) -> Result<Vec<i64>, ApiError> {
+        let payload = serde_json::to_string(message).map_err(|e| e.to_string())?;
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        for (recipient, device) in targets {
+            let queued: usize = tx.query_row(
+                "SELECT COUNT(*) FROM message_queue
+                 WHERE user_id = (SELECT user_id FROM users WHERE name = ?1) AND device_id = ?2",
+                params![recipient, device],
+                |row| row.get(0),
+            )?;
+
+            if queued >= self.max_queued_messages {
+                return Err(ApiError::new(
+                    ErrorCode::QueueFull,
+                    format!("Message queue of {} ({}) is full", recipient, device),
+                ));
+            }
+        }
+
+        let mut queue_ids = Vec::with_capacity(targets.len());
+        for (recipient, device) in targets {
+            if let Err(e) = tx.execute(
+                "INSERT INTO message_queue(user_id, device_id, message_id, payload, queued_at, attachment)
+                 VALUES ((SELECT user_id FROM users WHERE name = ?1), ?2, ?3, ?4, ?5, ?6)",
+                params![recipient, device, message.message_id, payload, message.timestamp, attachment],
+            ) {
+                error!("couldnt queue message {} for {} ({}): {}", message.message_id, recipient, device, e);
+                return Err(ApiError::new(ErrorCode::Internal, "Couldnt queue message"));
+            }
+
+            debug!("queued message {} for {} ({})", message.message_id, recipient, device);
+            queue_ids.push(tx.last_insert_rowid());
+        }
+
+        tx.commit()?;
+
+        Ok(queue_ids)
+    }
+
+    /// returns the unacknowledged messages of a device queued after `after` in the order they were queued
+    pub fn queued_messages(&self, recipient: String, device: String, after: i64) -> Result<Vec<QueuedMessage>, String> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT queue_id, payload, attachment FROM message_queue
+                 WHERE user_id = (SELECT user_id FROM users WHERE name = ?1) AND device_id = ?2
+                 AND queue_id > ?3
+                 ORDER BY queue_id ASC",
+            )
+            .unwrap();
+
+        let rows: Vec<(i64, String, Option<Vec<u8>>)> = stmt
+            .query_map(params![recipient, device, after], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+
+        let mut messages = Vec::new();
+        for (queue_id, payload, attachment) in rows {
+            match serde_json::from_str::<MsgPayload>(&payload) {
+                Ok(message) => messages.push(QueuedMessage {
+                    message: MsgPayload {
+                        queue_id: Some(queue_id),
+                        ..message
+                    },
+                    attachment,
+                }),
+                Err(e) => info!("dropping unreadable queued message {}: {}", queue_id, e),
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// removes the message a device of the recipient acknowledged and returns it.
+    /// Without a `queue_id` only the oldest entry with the `message_id` goes,
+    /// another sender may have picked the same id.
+    pub fn ack_message(
+        &self,
+        recipient: String,
+        device: String,
+        message_id: String,
+        queue_id: Option<i64>,
+    ) -> Result<Vec<MsgPayload>, String> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn
+            .prepare(
+                "DELETE FROM message_queue WHERE queue_id = (
+                     SELECT queue_id FROM message_queue
+                     WHERE user_id = (SELECT user_id FROM users WHERE name = ?1)
+                     AND device_id = ?2 AND (queue_id = ?4 OR (?4 IS NULL AND message_id = ?3))
+                     ORDER BY queue_id ASC LIMIT 1
+                 )
+                 RETURNING payload",
+            )
+            .unwrap();
+
+        let rows: Vec<String> = stmt
+            .query_map(params![recipient, device, message_id, queue_id], |row| row.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|payload| serde_json::from_str(payload).ok())
+            .collect())
+    }
+
+    /// creates a group with the creator as its admin, the given members are added right away
+    pub fn create_group(&self, creator: String, name: String, members: Vec<String>) -> Result<GroupInfo, ApiError> {
+        let mut conn = self.conn.lock().unwrap();
+        let now = now();
+        let group_id = Uuid::new_v4().to_string();
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        let admin_id = user_id(&tx, &creator)?;
+
+        tx.execute(
+            "INSERT INTO groups(group_id, name, admin_id, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![group_id, name, admin_id, now],
+        )
+        .map_err(|e| e.to_string())?;
+
+        {
+            let mut stmt = tx
+                .prepare("INSERT OR IGNORE INTO group_members(group_id, user_id, joined_at) VALUES (?, ?, ?)")
+                .map_err(|e| e.to_string())?;
+
+            stmt.execute(params![group_id, admin_id, now])
+                .map_err(|e| e.to_string())?;
+
+            for member in &members {
+                stmt.execute(params![group_id, user_id(&tx, member)?, now])
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+
+        let group = group_info(&tx, &group_id)?.ok_or("Couldnt create group".to_string())?;
+
+        tx.commit().map_err(|e| e.to_string())?;
+
+        info!("{} created group {}", creator, group_id);
+
+        Ok(group)
+    }
+
+    /// registers a blob that is about to be uploaded, group ids among the
+    /// recipients stand for every other member of the group
+    pub fn create_blob(&self, owner: String, size: u64, recipients: Vec<String>, ttl: u64) -> Result<String, ApiError> {
+        let mut conn = self.conn.lock().unwrap();
+        let now = now();
+        let blob_id = Uuid::new_v4().to_string();
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        let owner_id = user_id(&tx, &owner)?;
+
+        tx.execute(
+            "INSERT INTO blobs(blob_id, owner_id, size, created_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![blob_id, owner_id, size, now, now + ttl],
+        )
+        .map_err(|e| e.to_string())?;
+
+        {
+            let mut stmt = tx
+                .prepare("INSERT OR IGNORE INTO blob_recipients(blob_id, user_id) VALUES (?, ?)")
+                .map_err(|e| e.to_string())?;
+
+            for recipient in &recipients {
+                let users = match group_info(&tx, recipient)? {
+                    Some(group) => group.members,
+                    None => vec![recipient.clone()],
+                };
+
+                for user in users.iter().filter(|user| **user != owner) {
+                    stmt.execute(params![blob_id, user_id(&tx, user)?])
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+
+        tx.commit().map_err(|e| e.to_string())?;
+
+        info!("{} created blob {} ({} bytes)", owner, blob_id, size);
+
+        Ok(blob_id)
+    }
+
+    /// looks up a blob the user may access, which is the owner and every recipient
+    pub fn blob(&self, username: String, blob_id: String) -> Result<BlobMeta, ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row(
+            "SELECT b.name, a.size, a.complete FROM blobs a
+             JOIN users b ON a.owner_id = b.user_id
+             WHERE a.blob_id = ?1 AND a.expires_at > ?3 AND (b.name = ?2 OR EXISTS (
+                 SELECT 1 FROM blob_recipients c JOIN users d ON c.user_id = d.user_id
+                 WHERE c.blob_id = a.blob_id AND d.name = ?2
+             ))",
+            params![blob_id, username, now()],
+            |row| {
+                Ok(BlobMeta {
+                    owner: row.get(0)?,
+                    size: row.get(1)?,
+                    complete: row.get(2)?,
+                })
+            },
+        )
+        .optional()?
+        .ok_or_else(|| ApiError::new(ErrorCode::BlobNotFound, "No such blob"))
+    }
+
+    pub fn complete_blob(&self, blob_id: String) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("UPDATE blobs SET complete = 1 WHERE blob_id = ?", params![blob_id])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn delete_blob(&self, blob_id: String) -> Result<(), String> {
+        let mut conn = self.conn.lock().unwrap();
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM blob_recipients WHERE blob_id = ?", params![blob_id])
+            .map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM blobs WHERE blob_id = ?", params![blob_id])
+            .map_err(|e| e.to_string())?;
+
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    /// notes that a recipient downloaded the whole blob, returns true and
+    /// forgets the blob once every recipient has it
+    pub fn blob_fetched(&self, username: String, blob_id: String) -> Result<bool, String> {
+        let mut conn = self.conn.lock().unwrap();
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        tx.execute(
+            "UPDATE blob_recipients SET fetched = 1
+             WHERE blob_id = ?1 AND user_id = (SELECT user_id FROM users WHERE name = ?2)",
+            params![blob_id, username],
+        )
+        .map_err(|e| e.to_string())?;
+
+        let remaining: u64 = tx
+            .query_row(
+                "SELECT COUNT(*) FROM blob_recipients WHERE blob_id = ? AND fetched = 0",
+                params![blob_id],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+
+        if remaining > 0 {
+            tx.commit().map_err(|e| e.to_string())?;
+            return Ok(false);
+        }
+
+        tx.execute("DELETE FROM blob_recipients WHERE blob_id = ?", params![blob_id])
+            .map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM blobs WHERE blob_id = ?", params![blob_id])
+            .map_err(|e| e.to_string())?;
+
+        tx.commit().map_err(|e| e.to_string())?;
+
+        Ok(true)
+    }
+
+    /// forgets every blob whose TTL ran out and returns their ids
+    pub fn remove_expired_blobs(&self) -> Result<Vec<String>, String> {
+        let mut conn = self.conn.lock().unwrap();
+
+        let now = now();
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        tx.execute(
+            "DELETE FROM blob_recipients
+             WHERE blob_id IN (SELECT blob_id FROM blobs WHERE expires_at <= ?)",
+            params![now],
+        )
+        .map_err(|e| e.to_string())?;
+
+        let expired: Vec<String> = {
+            let mut stmt = tx
+                .prepare("DELETE FROM blobs WHERE expires_at <= ? RETURNING blob_id")
+                .map_err(|e| e.to_string())?;
+
+            let rows = stmt
+                .query_map(params![now], |row| row.get(0))
+                .map_err(|e| e.to_string())?
+                .collect::<Result<_, _>>()
+                .map_err(|e| e.to_string())?;
+            rows
+        };
+
+        tx.commit().map_err(|e| e.to_string())?;
+
+        Ok(expired)
+    }
+
+    /// the group with the given id, `None` if there is no such group
+    pub fn group(&self, group_id: String) -> Result<Option<GroupInfo>, String> {
+        let conn = self.conn.lock().unwrap();
+
+        group_info(&conn, &group_id)
+    }
+
+    /// adds a user to a group, only the admin may invite
+    pub fn add_group_member(&self, admin: String, group_id: String, username: String) -> Result<GroupInfo, ApiError> {
+        let mut conn = self.conn.lock().unwrap();
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        let group = group_info(&tx, &group_id)?.ok_or_else(group_not_found)?;
+        if group.admin != admin {
+            return Err(ApiError::new(ErrorCode::NotGroupAdmin, "Only the group admin can invite"));
+        }
+        if group.members.contains(&username) {
+            return Err(ApiError::new(ErrorCode::AlreadyGroupMember, format!("{} is already a member", username)));
+        }
+
+        tx.execute(
+            "INSERT INTO group_members(group_id, user_id, joined_at) VALUES (?1, ?2, ?3)",
+            params![group_id, user_id(&tx, &username)?, now()],
+        )
+        .map_err(|e| e.to_string())?;
+
+        let group = group_info(&tx, &group_id)?.ok_or_else(group_not_found)?;
+
+        tx.commit().map_err(|e| e.to_string())?;
+
+        info!("{} invited {} to group {}", admin, username, group_id);
+
+        Ok(group)
+    }
+
+    /// removes a user from a group, either because they left or because the
+    /// admin kicked them. The longest member takes over when the admin leaves
+    /// and the group is deleted once nobody is left.
+    pub fn remove_group_member(&self, actor: String, group_id: String, username: String) -> Result<GroupInfo, ApiError> {
+        let mut conn = self.conn.lock().unwrap();
+
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        let mut group = group_info(&tx, &group_id)?.ok_or_else(group_not_found)?;
+        if actor != username && group.admin != actor {
+            return Err(ApiError::new(ErrorCode::NotGroupAdmin, "Only the group admin can kick"));
+        }
+        if !group.members.contains(&username) {
+            return Err(ApiError::new(ErrorCode::NotGroupMember, format!("{} is not a member", username)));
+        }
+
+        tx.execute(
+            "DELETE FROM group_members WHERE group_id = ?1 AND user_id = ?2",
+            params![group_id, user_id(&tx, &username)?],
+        )
+        .map_err(|e| e.to_string())?;
+
+        match group_info(&tx, &group_id)? {
+            Some(v) if !v.members.is_empty() => {
+                group = v;
+                if group.admin == username {
+                    tx.execute(
+                        "UPDATE groups SET admin_id = (
+                             SELECT user_id FROM group_members WHERE group_id = ?1
+                             ORDER BY joined_at ASC, rowid ASC LIMIT 1
+                         ) WHERE group_id = ?1",
+                        params![group_id],
+                    )
+                    .map_err(|e| e.to_string())?;
+
+                    group = group_info(&tx, &group_id)?.ok_or_else(group_not_found)?;
+                }
+            }
+            _ => {
+                tx.execute("DELETE FROM groups WHERE group_id = ?", params![group_id])
+                    .map_err(|e| e.to_string())?;
+                group.members.clear();
+                info!("deleted empty group {}", group_id);
+            }
+        }
+
+        tx.commit().map_err(|e| e.to_string())?;
+
+        info!("{} removed {} from group {}", actor, username, group_id);
+
+        Ok(group)
+    }
+
+    /// when a device of the user was last connected, `None` if never
+    pub fn last_seen(&self, username: String) -> Result<Option<u64>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        let last_seen: u64 = conn
+            .query_row("SELECT last_seen FROM users WHERE name = ?", params![username], |row| row.get(0))
+            .optional()?
+            .ok_or_else(|| user_not_found(&username))?;
+
+        Ok(Some(last_seen).filter(|&last_seen| last_seen > 0))
+    }
+
+    /// remembers that a device of the user is connected right now
+    pub fn touch_last_seen(&self, username: String) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute("UPDATE users SET last_seen = ?1 WHERE name = ?2", params![now(), username])
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn set_presence_visibility(&self, username: String, visibility: PresenceVisibility) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let id = user_id(&conn, &username)?;
+
+        conn.execute(
+            "UPDATE users SET presence_visibility = ?1 WHERE user_id = ?2",
+            params![visibility, id],
+        )?;
+
+        Ok(())
+    }
+
+    /// whether `viewer` may see if the user is online and when they were last seen
+    pub fn may_see_presence(&self, username: String, viewer: String) -> Result<bool, ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        let visibility: PresenceVisibility = conn
+            .query_row(
+                "SELECT presence_visibility FROM users WHERE name = ?",
+                params![username],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| user_not_found(&username))?;
+
+        if username == viewer {
+            return Ok(true);
+        }
+
+        match visibility {
+            PresenceVisibility::Everyone => Ok(true),
+            PresenceVisibility::Nobody => Ok(false),
+            PresenceVisibility::Groups => Ok(conn.query_row(
+                "SELECT EXISTS(
+                     SELECT 1 FROM group_members a
+                     JOIN group_members b ON a.group_id = b.group_id
+                     WHERE a.user_id = (SELECT user_id FROM users WHERE name = ?1)
+                     AND b.user_id = (SELECT user_id FROM users WHERE name = ?2)
+                 )",
+                params![username, viewer],
+                |row| row.get(0),
+            )?),
+        }
+    }
+
+    /// writes everything sqlite still caches to disk, called on shutdown
+    pub fn flush(&self) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.cache_flush().map_err(|e| e.to_string())
+    }
+
+    /// every account, ordered by name
+    pub fn list_users(&self) -> Result<Vec<UserSummary>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare(&format!("{} ORDER BY a.name", USER_SUMMARY))?;
+        let users = stmt
+            .query_map([], user_summary)?
+            .collect::<Result<_, _>>()?;
+
+        Ok(users)
+    }
+
+    pub fn user_summary(&self, username: String) -> Result<UserSummary, ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.query_row(&format!("{} WHERE a.name = ?", USER_SUMMARY), params![username], user_summary)
+            .optional()?
+            .ok_or_else(|| user_not_found(&username))
+    }
+
+    /// the devices of a user with their key bundles and remaining onetime keys
+    pub fn user_devices(&self, username: String) -> Result<Vec<DeviceSummary>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let id = user_id(&conn, &username)?;
+
+        let mut stmt = conn.prepare(
+            "SELECT a.device_id, a.identity, a.prekey, a.prekey_created_at,
+                 (SELECT COUNT(*) FROM one_time_keys b WHERE b.bundle_id = a.bundle_id),
+                 (SELECT COUNT(*) FROM message_queue c
+                  WHERE c.user_id = a.user_id AND c.device_id = a.device_id)
+             FROM keybundles a WHERE a.user_id = ? ORDER BY a.bundle_id",
+        )?;
+        let devices = stmt
+            .query_map(params![id], |row| {
+                Ok(DeviceSummary {
+                    device: row.get(0)?,
+                    identity: row.get(1)?,
+                    prekey: row.get(2)?,
+                    prekey_created_at: row.get(3)?,
+                    onetime_keys: row.get(4)?,
+                    queued: row.get(5)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(devices)
+    }
+
+    /// deletes an account with its devices, tokens, queue and blobs. The user
+    /// leaves their groups first, so admin rights are handed over like on `leave`.
+    /// Returns the ids of the deleted blobs so their files can be removed.
+    pub fn delete_user(&self, username: String) -> Result<Vec<String>, ApiError> {
+        let groups: Vec<String> = {
+            let conn = self.conn.lock().unwrap();
+            let id = user_id(&conn, &username)?;
+
+            let mut stmt = conn.prepare("SELECT group_id FROM group_members WHERE user_id = ?")?;
+            let groups = stmt
+                .query_map(params![id], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            groups
+        };
+
+        for group in groups {
+            self.remove_group_member(username.clone(), group, username.clone())?;
+        }
+
+        let mut conn = self.conn.lock().unwrap();
+        let id = user_id(&conn, &username)?;
+
+        let tx = conn.transaction()?;
+
+        for statement in [
+            "DELETE FROM tokens WHERE user_id = ?1",
+            "DELETE FROM message_queue WHERE user_id = ?1",
+            "DELETE FROM one_time_keys WHERE bundle_id IN (SELECT bundle_id FROM keybundles WHERE user_id = ?1)",
+            "DELETE FROM previous_prekeys WHERE bundle_id IN (SELECT bundle_id FROM keybundles WHERE user_id = ?1)",
+            "DELETE FROM keybundles WHERE user_id = ?1",
+            "DELETE FROM blob_recipients
+             WHERE user_id = ?1 OR blob_id IN (SELECT blob_id FROM blobs WHERE owner_id = ?1)",
+            "DELETE FROM login_failures WHERE user_id = ?1",
+        ] {
+            tx.execute(statement, params![id])?;
+        }
+
+        let blobs: Vec<String> = {
+            let mut stmt = tx.prepare("DELETE FROM blobs WHERE owner_id = ? RETURNING blob_id")?;
+            let rows = stmt
+                .query_map(params![id], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            rows
+        };
+
+        tx.execute("DELETE FROM users WHERE user_id = ?", params![id])?;
+
+        tx.commit()?;
+
+        info!("deleted user {}", username);
+
+        Ok(blobs)
+    }
+
+    /// disables or enables an account, disabling also revokes its tokens
+    pub fn set_disabled(&self, username: String, disabled: bool) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let id = user_id(&conn, &username)?;
+
+        conn.execute("UPDATE users SET disabled = ?1 WHERE user_id = ?2", params![disabled, id])?;
+        if disabled {
+            conn.execute("DELETE FROM tokens WHERE user_id = ?", params![id])?;
+        }
+
+        info!("{} {}", if disabled { "disabled" } else { "enabled" }, username);
+
+        Ok(())
+    }
+
+    /// sets a new password hash, revokes every token and lifts a lockout
+    pub fn reset_password(&self, username: String, password_hash: String) -> Result<(), ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let id = user_id(&conn, &username)?;
+
+        conn.execute("UPDATE users SET password = ?1 WHERE user_id = ?2", params![password_hash, id])?;
+        conn.execute("DELETE FROM tokens WHERE user_id = ?", params![id])?;
+        conn.execute("DELETE FROM login_failures WHERE user_id = ?", params![id])?;
+
+        info!("reset the password of {}", username);
+
+        Ok(())
+    }
+
+    /// the queued messages of a user, or of one of their devices
+    pub fn inspect_queue(&self, username: String, device: Option<String>) -> Result<Vec<QueueEntry>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let id = user_id(&conn, &username)?;
+
+        let mut stmt = conn.prepare(
+            "SELECT device_id, message_id, payload, queued_at,
+                 LENGTH(payload) + COALESCE(LENGTH(attachment), 0)
+             FROM message_queue WHERE user_id = ?1 AND (?2 IS NULL OR device_id = ?2)
+             ORDER BY queue_id ASC",
+        )?;
+        let rows: Vec<(String, String, String, u64, u64)> = stmt
+            .query_map(params![id, device], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+            })?
+            .collect::<Result<_, _>>()?;
+
+        let entries = rows
+            .into_iter()
+            .map(|(device, message_id, payload, queued_at, size)| QueueEntry {
+                device,
+                message_id,
+                author: serde_json::from_str::<MsgPayload>(&payload)
+                    .map(|message| message.author)
+                    .unwrap_or_default(),
+                queued_at,
+                size,
+            })
+            .collect();
+
+        Ok(entries)
+    }
+
+    /// how many messages are queued for each user that has any
+    pub fn queue_depths(&self) -> Result<Vec<(String, u64)>, ApiError> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut stmt = conn.prepare(
+            "SELECT b.name, COUNT(*) FROM message_queue a
+             JOIN users b ON a.user_id = b.user_id
+             GROUP BY b.name ORDER BY b.name",
+        )?;
+        let depths = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(depths)
+    }
+
+    /// drops the queued messages of a user, or of one of their devices, and returns how many there were
+    pub fn purge_queue(&self, username: String, device: Option<String>) -> Result<usize, ApiError> {
+        let conn = self.conn.lock().unwrap();
+        let id = user_id(&conn, &username)?;
+
+        let purged = conn.execute(
+            "DELETE FROM message_queue WHERE user_id = ?1 AND (?2 IS NULL OR device_id = ?2)",
+            params![id, device],
+        )?;
+
+        info!("purged {} queued messages of {}", purged, username);
+
+        Ok(purged)
+    }
+}
+
+fn user_summary(row: &rusqlite::Row) -> rusqlite::Result<UserSummary> {
+    Ok(UserSummary {
+        name: row.get(0)?,
+        devices: row.get(1)?,
+        queued: row.get(2)?,
+        disabled: row.get(3)?,
+        locked_until: row.get(4)?,
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// hashes a password with argon2 and a random salt into a PHC string. Argon2
+/// is slow on purpose, so it runs on the blocking pool and never under a lock.
+pub async fn hash_password(password: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || argon2_hash(&password))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// checks a password against the hash `begin_login` found, on the blocking pool
+/// like `hash_password`
+pub async fn check_password(password: String, stored: String) -> Result<PasswordCheck, String> {
+    tokio::task::spawn_blocking(move || match PasswordHash::new(&stored) {
+        Ok(hash) => match Argon2::default().verify_password(password.as_bytes(), &hash) {
+            Ok(_) => Ok(PasswordCheck::Valid),
+            Err(_) => Ok(PasswordCheck::Invalid),
+        },
+        // accounts from before argon2 still have a plain sha256 digest stored
+        Err(_) if digest(password.clone()) == stored => argon2_hash(&password).map(PasswordCheck::Upgrade),
+        Err(_) => Ok(PasswordCheck::Invalid),
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+fn argon2_hash(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// stores the bundle and onetime keys of a new device
+fn insert_bundle(conn: &Connection, user_id: i32, device: &str, keybundle: &KeyBundle) -> Result<(), String> {
+    match conn.execute(
+        "INSERT INTO keybundles(identity, prekey, signature, user_id, prekey_created_at, device_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            keybundle.identity.public,
+            keybundle.prekey.public,
+            keybundle.signature.public,
+            user_id,
+            now(),
+            device
+        ],
+    ) {
+        Ok(_) => {
+            debug!("successfully registered bundle!");
+        }
+        Err(e) => {
+            error!("couldnt store the keybundle of device {}: {}", device, e);
+            return Err("Couldnt register keybundle".to_string());
+        }
+    }
+
+    let bundle_id = conn.last_insert_rowid();
+
+    let mut stmt = conn
+        .prepare("INSERT INTO one_time_keys(key, bundle_id) VALUES (?, ?)")
+        .map_err(|e| e.to_string())?;
+
+    for otk in &keybundle.onetime_keys {
+        stmt.execute(params![otk.public, bundle_id])
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+fn count_onetime_keys(conn: &Connection, username: &str, device: &str) -> Result<u64, String> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM one_time_keys a
+         JOIN keybundles b ON a.bundle_id = b.bundle_id
+         WHERE b.user_id = (SELECT user_id FROM users WHERE name = ?1) AND b.device_id = ?2",
+        params![username, device],
+        |row| row.get(0),
+    )
+    .map_err(|e| e.to_string())
+}
+
+fn user_id(conn: &Connection, username: &str) -> Result<i32, ApiError> {
+    conn.query_row("SELECT user_id FROM users WHERE name = ?", params![username], |row| row.get(0))
+        .optional()?
+        .ok_or_else(|| user_not_found(username))
+}
+
+/// rejects a login to an account that is locked or whose last failure was too recent
+fn check_account_lock(conn: &Connection, user_id: i32, now: u64, limiter: &RateLimiter) -> Result<(), ApiError> {
+    let row: Option<(u32, u64, u64)> = conn
+        .query_row(
+            "SELECT failures, last_failure_at, locked_until FROM login_failures WHERE user_id = ?",
+            params![user_id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+
+    let Some((count, last_failure_at, locked_until)) = row else {
+        return Ok(());
+    };
+
+    if locked_until > now {
+        return Err(account_locked(locked_until - now));
+    }
+    if last_failure_at + limiter.config().lockout_secs <= now {
+        return Ok(());
+    }
+
+    limiter.check_backoff(Failures { count, last_failure_at }, now)
+}
+
+/// counts a failed login of an account and locks it once there were too
+/// many, returns whether it got locked
+fn record_login_failure(conn: &Connection, user_id: i32, now: u64, limiter: &RateLimiter) -> Result<bool, ApiError> {
+    let config = limiter.config();
+
+    // failures from before the last lockout period start over
+    conn.execute(
+        "INSERT INTO login_failures(user_id, failures, last_failure_at) VALUES (?1, 1, ?2)
+         ON CONFLICT(user_id) DO UPDATE SET
+             failures = CASE WHEN last_failure_at + ?3 <= ?2 THEN 1 ELSE failures + 1 END,
+             last_failure_at = ?2",
+        params![user_id, now, config.lockout_secs],
+    )?;
+
+    if config.lockout_attempts == 0 {
+        return Ok(false);
+    }
+
+    let locked = conn.execute(
+        "UPDATE login_failures SET failures = 0, locked_until = ?2 WHERE user_id = ?1 AND failures >= ?3",
+        params![user_id, now + config.lockout_secs, config.lockout_attempts],
+    )?;
+
+    Ok(locked > 0)
+}
+
+fn account_locked(retry_after: u64) -> ApiError {
+    ApiError::new(
+        ErrorCode::AccountLocked,
+        format!("Account is locked after too many failed logins, try again in {} seconds", retry_after),
+    )
+    .retry_after(retry_after)
+}
+
+fn account_disabled() -> ApiError {
+    ApiError::new(ErrorCode::AccountDisabled, "Account is disabled")
+}
+
+fn user_not_found(username: &str) -> ApiError {
+    ApiError::new(ErrorCode::UserNotFound, format!("User {} not found", username))
+}
+
+fn group_not_found() -> ApiError {
+    ApiError::new(ErrorCode::GroupNotFound, "No such group")
+}
+
+/// the same answer for unknown users and wrong passwords, so accounts can't be probed
+fn invalid_credentials() -> ApiError {
+    ApiError::new(ErrorCode::InvalidCredentials, "Couldnt find User")
+}
+
+fn invalid_keybundle(e: String) -> ApiError {
+    ApiError::new(ErrorCode::InvalidKeybundle, format!("Invalid keybundle: {}", e))
+}
+
+fn group_info(conn: &Connection, group_id: &str) -> Result<Option<GroupInfo>, String> {
+    let group: Option<(String, String)> = conn
+        .query_row(
+            "SELECT a.name, b.name FROM groups a
+             JOIN users b ON a.admin_id = b.user_id
+             WHERE a.group_id = ?",
+            params![group_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(|e| e.to_string())?;
+
+    let (name, admin) = match group {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT b.name FROM group_members a
+             JOIN users b ON a.user_id = b.user_id
+             WHERE a.group_id = ?
+             ORDER BY a.joined_at ASC, a.rowid ASC",
+        )
+        .map_err(|e| e.to_string())?;
+
+    let members = stmt
+        .query_map(params![group_id], |row| row.get(0))
+        .map_err(|e| e.to_string())?
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok(Some(GroupInfo {
+        id: group_id.to_string(),
+        name,
+        admin,
+        members,
+    }))
+}
+
+fn issue_token(conn: &Connection, user_id: i32, device: &str) -> Result<Uuid, String> {
+    let uuid = Uuid::new_v4();
+    let now = now();
+
+    // expired tokens are useless, so clean them up whenever new ones get issued
+    conn.execute("DELETE FROM tokens WHERE expires_at <= ?", params![now])
+        .map_err(|e| e.to_string())?;
+
+    match conn.execute(
+        "INSERT INTO tokens(token_hash, user_id, device_id, created_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![token_hash(&uuid.to_string()), user_id, device, now, now + TOKEN_TTL_SECS],
+    ) {
+        Ok(_) => Ok(uuid),
+        Err(e) => {
+            error!("couldnt issue a token: {}", e);
+            Err("Couldnt issue token".to_string())
+        }
+    }
+}
+
+/// only the digest of a token is stored, a leaked database doesn't hand out sessions
+fn token_hash(token: &str) -> String {
+    digest(token)
+}
+
+async fn open_database(path: &Path) -> Result<Connection, String> {
+    let mut connection = Connection::open(path)
+        .map_err(|e| format!("couldnt open {}: {}", path.display(), e))?;
+
+    migrations::migrate(&mut connection)?;
+
+    Ok(connection)
+}
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::config::RateLimitConfig;
+
+    // a bundle whose prekey signature verifies, the keys come from the keys.rs tests
+    const IDENTITY: &str = "A6EHv/POEL4dcN0Y50vAmWfk1jCbpQ1fHdyGZBJVMbg=";
+    const PREKEY: &str = "E75P6uryBMf9M1j8nAByGIHRdCeBKCJ+xnTzf3/pe20=";
+    const SIGNATURE: &str =
+        "GDiKis51P1VKEXgFMHUwY5dqwktVnieQQo7tnXKoZf7FEV0iUDv9m4SEk8e8Nqz+FvXw801bHWDqoLFXOnMmBQ==";
+
+    fn key(public: &str) -> KeyPairB64 {
+        KeyPairB64 {
+            public: public.to_string(),
+            private: None,
+        }
+    }
+
+    fn bundle() -> KeyBundle {
+        KeyBundle {
+            identity: key(IDENTITY),
+            prekey: key(PREKEY),
+            signature: key(SIGNATURE),
+            onetime_keys: vec![key(PREKEY)],
+            ephemeral_key: None,
+            prekey_created_at: None,
+            device: None,
+            previous_prekeys: Vec::new(),
+        }
+    }
+
+    async fn database(rate_limit: RateLimitConfig) -> UserDatabase {
+        let mut config = Config::default();
+        config.database.path = ":memory:".into();
+        config.rate_limit = rate_limit;
+
+        UserDatabase::new(&config).await.unwrap()
+    }
+
+    async fn database_at(path: &Path) -> UserDatabase {
+        let mut config = Config::default();
+        config.database.path = path.to_path_buf();
+
+        UserDatabase::new(&config).await.unwrap()
+    }
+
+    fn message(message_id: &str) -> MsgPayload {
+        MsgPayload {
+            content: None,
+            timestamp: 0,
+            auth: None,
+            message_id: message_id.to_string(),
+            author: "bob".to_string(),
+            recipient: "alice".to_string(),
+            queue_id: None,
+        }
+    }
+
+    fn enqueue(db: &UserDatabase, device: &str, message_id: &str) -> i64 {
+        let targets = [("alice".to_string(), device.to_string())];
+        db.enqueue_message(&targets, &message(message_id), None).unwrap()[0]
+    }
+
+    fn queued_ids(db: &UserDatabase, device: &str) -> Vec<String> {
+        db.queued_messages("alice".to_string(), device.to_string(), 0)
+            .unwrap()
+            .into_iter()
+            .map(|queued| queued.message.message_id)
+            .collect()
+    }
+
+    /// registers a user with the password "pw". The legacy sha256 hash keeps
+    /// the tests fast, argon2 takes ages without optimizations.
+    fn register(db: &UserDatabase, username: &str) -> Uuid {
+        db.register_user(username.to_string(), digest("pw"), "default".to_string(), bundle())
+            .unwrap()
+    }
+
+    async fn login(db: &tokio::sync::Mutex<UserDatabase>, password: &str, ip: IpAddr) -> Result<Uuid, ApiError> {
+        let attempt = db.lock().await.begin_login("alice".to_string(), ip)?;
+        let check = check_password(password.to_string(), attempt.password_hash.clone()).await?;
+        db.lock().await.finish_login(attempt, check, "default".to_string(), None)
+    }
+
+    fn lockout_config() -> RateLimitConfig {
+        RateLimitConfig {
+            free_attempts: 100,
+            lockout_attempts: 3,
+            lockout_secs: 60,
+            ..RateLimitConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_bad_logins_lock_the_account() {
+        let db = Arc::new(tokio::sync::Mutex::new(database(lockout_config()).await));
+        register(&*db.lock().await, "alice");
+
+        // every guess comes from its own address, so only the account limit applies
+        let guesses: Vec<_> = (0..10)
+            .map(|i| {
+                let db = db.clone();
+                tokio::spawn(async move { login(&db, "wrong", Ipv4Addr::new(192, 0, 2, i).into()).await })
+            })
+            .collect();
+
+        let mut codes = Vec::new();
+        for guess in guesses {
+            codes.push(guess.await.unwrap().unwrap_err().code);
+        }
+
+        let invalid = codes.iter().filter(|code| **code == ErrorCode::InvalidCredentials).count();
+        let locked = codes.iter().filter(|code| **code == ErrorCode::AccountLocked).count();
+        assert_eq!((invalid, locked), (2, 8));
+
+        let error = login(&db, "pw", Ipv4Addr::new(192, 0, 2, 100).into()).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::AccountLocked);
+    }
+
+    #[tokio::test]
+    async fn successful_login_clears_failures() {
+        let db = tokio::sync::Mutex::new(database(lockout_config()).await);
+        register(&*db.lock().await, "alice");
+        let ip = Ipv4Addr::new(192, 0, 2, 1).into();
+
+        for _ in 0..2 {
+            login(&db, "wrong", ip).await.unwrap_err();
+        }
+        login(&db, "pw", ip).await.unwrap();
+        for _ in 0..2 {
+            login(&db, "wrong", ip).await.unwrap_err();
+        }
+
+        login(&db, "pw", ip).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_messages_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("cipherchat-queue-{}.db", std::process::id()));
+
+        let db = database_at(&path).await;
+        register(&db, "alice");
+        for message_id in ["1", "2", "3"] {
+            enqueue(&db, "default", message_id);
+        }
+        drop(db);
+
+        let db = database_at(&path).await;
+        let queued = queued_ids(&db, "default");
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(queued, ["1", "2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn ack_removes_the_named_queue_entry() {
+        let db = database(RateLimitConfig::default()).await;
+        register(&db, "alice");
+
+        // several senders picked the same message id
+        let mut queue_ids = Vec::new();
+        for _ in 0..3 {
+            queue_ids.push(enqueue(&db, "default", "1"));
+        }
+
+        let acked = db
+            .ack_message("alice".to_string(), "default".to_string(), "1".to_string(), Some(queue_ids[1]))
+            .unwrap();
+        assert_eq!(acked.len(), 1);
+
+        let left: Vec<_> = db
+            .queued_messages("alice".to_string(), "default".to_string(), 0)
+            .unwrap()
+            .into_iter()
+            .map(|queued| queued.message.queue_id.unwrap())
+            .collect();
+        assert_eq!(left, [queue_ids[0], queue_ids[2]]);
+
+        // without a queue id the oldest one goes
+        db.ack_message("alice".to_string(), "default".to_string(), "1".to_string(), None)
+            .unwrap();
+        let left = db.queued_messages("alice".to_string(), "default".to_string(), 0).unwrap();
+        assert_eq!(left[0].message.queue_id, Some(queue_ids[2]));
+        assert_eq!(left.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn backlog_starts_after_the_given_queue_id() {
+        let db = database(RateLimitConfig::default()).await;
+        register(&db, "alice");
+
+        let first = enqueue(&db, "default", "1");
+        enqueue(&db, "default", "2");
+
+        let queued = db.queued_messages("alice".to_string(), "default".to_string(), first).unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].message.message_id, "2");
+    }
+
+    #[tokio::test]
+    async fn only_the_token_digest_is_stored() {
+        let db = database(RateLimitConfig::default()).await;
+        let token = register(&db, "alice").to_string();
+
+        let stored: Vec<String> = {
+            let conn = db.conn.lock().unwrap();
+            let mut stmt = conn.prepare("SELECT token_hash FROM tokens").unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.collect::<Result<_, _>>().unwrap()
+        };
+        assert_eq!(stored, [digest(token.as_str())]);
+    }
+
+    #[tokio::test]
+    async fn resume_rotates_the_token() {
+        let db = database(RateLimitConfig::default()).await;
+        let token = register(&db, "alice").to_string();
+
+        let (username, device, fresh) = db.resume(token.clone()).unwrap();
+        assert_eq!((username.as_str(), device.as_str()), ("alice", "default"));
+
+        assert_eq!(db.resume(token).unwrap_err().code, ErrorCode::InvalidToken);
+        db.resume(fresh.to_string()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_and_revoked_tokens_are_refused() {
+        let db = database(RateLimitConfig::default()).await;
+        let expired = register(&db, "alice").to_string();
+
+        db.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE tokens SET expires_at = ?1", params![now()])
+            .unwrap();
+        assert_eq!(db.resume(expired).unwrap_err().code, ErrorCode::InvalidToken);
+
+        let token = register(&db, "bob").to_string();
+        db.revoke_token("bob".to_string(), token.clone()).unwrap();
+        assert_eq!(db.resume(token).unwrap_err().code, ErrorCode::InvalidToken);
+    }
+
+    #[tokio::test]
+    async fn every_device_has_its_own_bundle_and_queue() {
+        let db = database(RateLimitConfig::default()).await;
+        register(&db, "alice");
+        insert_bundle(&db.conn.lock().unwrap(), 1, "phone", &bundle()).unwrap();
+
+        assert_eq!(db.devices("alice".to_string()).unwrap(), ["default", "phone"]);
+        let bundles = db.fetch_bundles("alice".to_string()).unwrap();
+        let devices: Vec<_> = bundles.iter().map(|bundle| bundle.device.as_deref().unwrap()).collect();
+        assert_eq!(devices, ["default", "phone"]);
+
+        for device in ["default", "phone"] {
+            enqueue(&db, device, "1");
+        }
+
+        // an ack of one device leaves the copy of the other one alone
+        db.ack_message("alice".to_string(), "default".to_string(), "1".to_string(), None)
+            .unwrap();
+        assert!(queued_ids(&db, "default").is_empty());
+        assert_eq!(queued_ids(&db, "phone"), ["1"]);
+    }
+
+    #[tokio::test]
+    async fn replaced_prekeys_are_handed_out_for_the_grace_period() {
+        let db = database(RateLimitConfig::default()).await;
+        register(&db, "alice");
+
+        // the rotation may keep the same prekey, only one signed pair is at hand
+        db.rotate_prekey("alice".to_string(), "default".to_string(), key(PREKEY), key(SIGNATURE))
+            .unwrap();
+
+        let previous = db.fetch_bundles("alice".to_string()).unwrap().remove(0).previous_prekeys;
+        assert_eq!(previous.len(), 1);
+        assert_eq!(previous[0].signature.public, SIGNATURE);
+        assert!(previous[0].expires_at > now());
+
+        db.conn
+            .lock()
+            .unwrap()
+            .execute("UPDATE previous_prekeys SET retired_at = ?1", params![now() - db.prekey_grace_period])
+            .unwrap();
+        assert!(db.fetch_bundles("alice".to_string()).unwrap()[0].previous_prekeys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn full_queue_keeps_the_message_from_every_device() {
+        let mut config = Config::default();
+        config.database.path = ":memory:".into();
+        config.queue.max_messages_per_device = 1;
+        let db = UserDatabase::new(&config).await.unwrap();
+        register(&db, "alice");
+        insert_bundle(&db.conn.lock().unwrap(), 1, "phone", &bundle()).unwrap();
+
+        enqueue(&db, "phone", "1");
+
+        let targets = [
+            ("alice".to_string(), "default".to_string()),
+            ("alice".to_string(), "phone".to_string()),
+        ];
+        let error = db.enqueue_message(&targets, &message("2"), None).unwrap_err();
+        assert_eq!(error.code, ErrorCode::QueueFull);
+        assert!(queued_ids(&db, "default").is_empty());
+    }
+
+    #[test]
+    fn account_gets_locked_after_too_many_failures() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut conn).unwrap();
+        conn.execute("INSERT INTO users(name, password) VALUES ('alice', 'x')", []).unwrap();
+        let limiter = RateLimiter::new(lockout_config());
+
+        assert!(!record_login_failure(&conn, 1, 100, &limiter).unwrap());
+        assert!(!record_login_failure(&conn, 1, 110, &limiter).unwrap());
+        check_account_lock(&conn, 1, 110, &limiter).unwrap();
+        assert!(record_login_failure(&conn, 1, 120, &limiter).unwrap());
+
+        let error = check_account_lock(&conn, 1, 130, &limiter).unwrap_err();
+        assert_eq!(error.code, ErrorCode::AccountLocked);
+        assert_eq!(error.retry_after, Some(50));
+
+        check_account_lock(&conn, 1, 180, &limiter).unwrap();
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut conn).unwrap();
+        conn.execute("INSERT INTO users(name, password) VALUES ('alice', 'x')", []).unwrap();
+        let limiter = RateLimiter::new(lockout_config());
+
+        record_login_failure(&conn, 1, 100, &limiter).unwrap();
+        record_login_failure(&conn, 1, 110, &limiter).unwrap();
+
+        // the last failure is a lockout period ago, counting starts over
+        assert!(!record_login_failure(&conn, 1, 170, &limiter).unwrap());
+        assert!(!record_login_failure(&conn, 1, 171, &limiter).unwrap());
+        assert!(record_login_failure(&conn, 1, 172, &limiter).unwrap());
+    }
+}
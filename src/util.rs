@@ -1,44 +1,139 @@
-use std::str::Utf8Error;
-
-
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct MsgPayload{
-  pub content: Option<MsgContent>,
-  pub timestamp: u64,
-  pub auth: Option<OpAuthPayload>,
-  pub message_id: String,
-  pub author: String,
-  pub recipient: String
-}
-
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
-pub struct OpAuthPayload{
-  pub action: String,
-  pub user: String,
-  pub password: String,
-  pub keybundle: Option<KeyBundle>,
-  pub message: String,
-  pub success: Option<bool>,
-}
-
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct MsgContent{
-  pub ciphertext: String,
-  pub nonce: String,
-  pub cleartext: Option<String>
-}
-
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct KeyBundle{
-  pub identity: KeyPairB64,
-  pub prekey: KeyPairB64,
-  pub signature: KeyPairB64,
-  pub onetime_keys: Vec<KeyPairB64>,
-  pub ephemeral_key: Option<KeyPairB64>
-}
-
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-pub struct KeyPairB64{
-  pub public: String,
-  pub private: Option<String>
-}
+use crate::protocol::ErrorCode;
+
+/// device id of clients that don't send one, accounts from before multi-device
+/// support have their only device registered under it
+pub const DEFAULT_DEVICE: &str = "default";
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct MsgPayload{
+  pub content: Option<MsgContent>,
+  pub timestamp: u64,
+  pub auth: Option<OpAuthPayload>,
+  pub message_id: String,
+  pub author: String,
+  pub recipient: String,
+  // server side id of the queue entry this frame was delivered from, acks
+  // name it because `message_id` is picked by the clients and may repeat
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub queue_id: Option<i64>
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct OpAuthPayload{
+  pub action: String,
+  pub user: String,
+  pub password: String,
+  pub keybundle: Option<KeyBundle>,
+  pub message: String,
+  pub success: Option<bool>,
+  #[serde(default)]
+  pub token: Option<String>,
+  #[serde(default)]
+  pub onetime_keys: Option<Vec<KeyPairB64>>,
+  #[serde(default)]
+  pub count: Option<u64>,
+  // every device bundle of a user, `keybundle` only holds the first one
+  #[serde(default)]
+  pub keybundles: Option<Vec<KeyBundle>>,
+  // the device of the session, `DEFAULT_DEVICE` if left out
+  #[serde(default)]
+  pub device: Option<String>,
+  #[serde(default)]
+  pub group: Option<GroupInfo>,
+  #[serde(default)]
+  pub blob: Option<BlobInfo>,
+  // what went wrong when `success` is false
+  #[serde(default)]
+  pub error: Option<ErrorCode>,
+  // presence of users, requests to (un)subscribe only fill in `user`
+  #[serde(default)]
+  pub presence: Option<Vec<PresenceInfo>>,
+  #[serde(default)]
+  pub visibility: Option<PresenceVisibility>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MsgContent{
+  pub ciphertext: String,
+  pub nonce: String,
+  pub cleartext: Option<String>
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct KeyBundle{
+  pub identity: KeyPairB64,
+  pub prekey: KeyPairB64,
+  pub signature: KeyPairB64,
+  pub onetime_keys: Vec<KeyPairB64>,
+  pub ephemeral_key: Option<KeyPairB64>,
+  // unix timestamp of when the signed prekey was uploaded, set by the server
+  #[serde(default)]
+  pub prekey_created_at: Option<u64>,
+  // the device this bundle belongs to, set by the server
+  #[serde(default)]
+  pub device: Option<String>,
+  // signed prekeys replaced less than the grace period ago, a peer that
+  // fetched one of them before the rotation may still start a session with it.
+  // Set by the server
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub previous_prekeys: Vec<PreviousPrekey>
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PreviousPrekey{
+  pub prekey: KeyPairB64,
+  pub signature: KeyPairB64,
+  // unix timestamp after which the server stops handing it out
+  pub expires_at: u64
+}
+
+// a group conversation, clients only fill in what the action needs
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GroupInfo{
+  pub id: String,
+  pub name: String,
+  pub admin: String,
+  pub members: Vec<String>
+}
+
+// whether a user is online and when a device of them was last connected,
+// `last_seen` stays empty for users that never were or hide from the viewer
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PresenceInfo{
+  pub user: String,
+  pub online: bool,
+  pub last_seen: Option<u64>
+}
+
+// who may see the presence of a user
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceVisibility{
+  #[default]
+  Everyone,
+  // only users that share a group with them
+  Groups,
+  Nobody
+}
+
+// an encrypted attachment in the blob store, `offset` and `length` describe
+// the chunk a frame carries
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct BlobInfo{
+  pub id: String,
+  pub size: u64,
+  pub offset: u64,
+  pub length: u64,
+  pub complete: bool,
+  // users or group ids that may download the blob
+  pub recipients: Vec<String>
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct KeyPairB64{
+  pub public: String,
+  pub private: Option<String>
+}
\ No newline at end of file